@@ -1,6 +1,7 @@
 //! Configuration for a Habitat Builder-API service
 
-use crate::db::config::DataStoreCfg;
+use crate::db::{config::DataStoreCfg,
+                models::keys::DEFAULT_MAX_KEY_BODY_SIZE};
 use artifactory_client::config::ArtifactoryCfg;
 use github_api_client::config::GitHubCfg;
 use habitat_core::{config::ConfigFile,
@@ -109,29 +110,53 @@ impl Default for S3Cfg {
 #[derive(Debug, Clone, Deserialize)]
 #[serde(default)]
 pub struct ApiCfg {
-    pub data_path:        PathBuf,
-    pub log_path:         PathBuf,
+    pub data_path:                  PathBuf,
+    pub log_path:                   PathBuf,
     /// Location of Builder encryption keys
-    pub key_path:         KeyCache,
-    pub targets:          Vec<PackageTarget>,
-    pub build_targets:    Vec<PackageTarget>,
-    pub features_enabled: String,
-    pub build_on_upload:  bool,
-    pub private_max_age:  usize,
+    pub key_path:                   KeyCache,
+    pub targets:                    Vec<PackageTarget>,
+    pub build_targets:              Vec<PackageTarget>,
+    pub features_enabled:           String,
+    pub build_on_upload:            bool,
+    pub private_max_age:            usize,
+    /// Lifetime, in seconds, of cached OAuth sessions
+    pub session_duration_secs:      u32,
+    /// Requests per minute allowed for an authenticated account, which is also
+    /// the largest burst allowed (0 disables)
+    pub rate_limit_per_minute:      u32,
+    /// Requests per minute allowed for a client IP before it authenticates,
+    /// which is also the largest burst allowed (0 disables)
+    pub anon_rate_limit_per_minute: u32,
+    /// Addresses of reverse proxies trusted to report the client IP in a
+    /// forwarding header. Requests from anywhere else are keyed on their peer
+    /// address.
+    pub trusted_proxies:            Vec<String>,
+    /// Routes that accept a token in an `access_token` query parameter, for
+    /// clients such as WebSockets that cannot send an Authorization header. An
+    /// entry ending in `*` matches any path with that prefix.
+    pub query_token_routes:         Vec<String>,
+    /// Largest origin encryption key body, in bytes, that will be stored
+    pub max_key_body_size:          usize,
 }
 
 impl Default for ApiCfg {
     fn default() -> Self {
-        ApiCfg { data_path:        PathBuf::from("/hab/svc/builder-api/data"),
-                 log_path:         env::temp_dir(),
-                 key_path:         KeyCache::new("/hab/svc/builder-api/files"),
-                 targets:          vec![target::X86_64_LINUX,
-                                        target::X86_64_LINUX_KERNEL2,
-                                        target::X86_64_WINDOWS,],
-                 build_targets:    vec![target::X86_64_LINUX, target::X86_64_WINDOWS],
-                 features_enabled: String::from("jobsrv"),
-                 build_on_upload:  true,
-                 private_max_age:  300, }
+        ApiCfg { data_path:                  PathBuf::from("/hab/svc/builder-api/data"),
+                 log_path:                   env::temp_dir(),
+                 key_path:                   KeyCache::new("/hab/svc/builder-api/files"),
+                 targets:                    vec![target::X86_64_LINUX,
+                                                  target::X86_64_LINUX_KERNEL2,
+                                                  target::X86_64_WINDOWS,],
+                 build_targets:              vec![target::X86_64_LINUX, target::X86_64_WINDOWS],
+                 features_enabled:           String::from("jobsrv"),
+                 build_on_upload:            true,
+                 private_max_age:            300,
+                 session_duration_secs:      3 * 24 * 60 * 60,
+                 rate_limit_per_minute:      0,
+                 anon_rate_limit_per_minute: 0,
+                 trusted_proxies:            vec![],
+                 query_token_routes:         vec![],
+                 max_key_body_size:          DEFAULT_MAX_KEY_BODY_SIZE, }
     }
 }
 
@@ -228,8 +253,11 @@ pub struct MemcacheCfgHosts {
 #[derive(Debug, Clone, Deserialize)]
 #[serde(default)]
 pub struct MemcacheCfg {
-    pub ttl:   u32,
-    pub hosts: Vec<MemcacheCfgHosts>,
+    pub ttl:        u32,
+    pub hosts:      Vec<MemcacheCfgHosts>,
+    /// Prepended to session cache keys so that several Builder instances can
+    /// share memcache servers without colliding
+    pub key_prefix: String,
 }
 
 impl Default for MemcacheCfgHosts {
@@ -242,8 +270,9 @@ impl Default for MemcacheCfgHosts {
 
 impl Default for MemcacheCfg {
     fn default() -> Self {
-        MemcacheCfg { hosts: vec![MemcacheCfgHosts::default()],
-                      ttl:   15, }
+        MemcacheCfg { hosts:      vec![MemcacheCfgHosts::default()],
+                      ttl:        15,
+                      key_prefix: String::new(), }
     }
 }
 
@@ -297,14 +326,20 @@ impl fmt::Display for MemcacheCfgHosts {
 #[derive(Debug, Clone, Deserialize)]
 #[serde(default)]
 pub struct JobsrvCfg {
-    pub host: String,
-    pub port: u16,
+    pub host:             String,
+    pub port:             u16,
+    /// Seconds to wait for a jobsrv RPC to complete before giving up
+    pub rpc_timeout_secs: u64,
+    /// Times to retry a jobsrv RPC that could not connect to jobsrv
+    pub rpc_retries:      u32,
 }
 
 impl Default for JobsrvCfg {
     fn default() -> Self {
-        JobsrvCfg { host: String::from("localhost"),
-                    port: 5580, }
+        JobsrvCfg { host:             String::from("localhost"),
+                    port:             5580,
+                    rpc_timeout_secs: 30,
+                    rpc_retries:      2, }
     }
 }
 
@@ -331,6 +366,12 @@ mod tests {
         features_enabled = "foo, bar"
         build_on_upload = false
         private_max_age = 400
+        session_duration_secs = 3600
+        rate_limit_per_minute = 600
+        anon_rate_limit_per_minute = 60
+        trusted_proxies = ["10.0.0.1"]
+        query_token_routes = ["/v1/jobs/*"]
+        max_key_body_size = 8192
 
         [http]
         listen = "0:0:0:0:0:0:0:1"
@@ -340,6 +381,7 @@ mod tests {
 
         [memcache]
         ttl = 11
+        key_prefix = "bldr-a:"
         [[memcache.hosts]]
         host = "192.168.0.1"
         port = 12345
@@ -369,6 +411,8 @@ mod tests {
         [jobsrv]
         host = "1.2.3.4"
         port = 1234
+        rpc_timeout_secs = 5
+        rpc_retries = 4
 
         [datastore]
         host = "1.1.1.1"
@@ -404,14 +448,24 @@ mod tests {
         assert_eq!(&config.api.features_enabled, "foo, bar");
         assert_eq!(config.api.build_on_upload, false);
         assert_eq!(config.api.private_max_age, 400);
+        assert_eq!(config.api.session_duration_secs, 3600);
+        assert_eq!(config.api.rate_limit_per_minute, 600);
+        assert_eq!(config.api.anon_rate_limit_per_minute, 60);
+        assert_eq!(config.api.trusted_proxies, vec!["10.0.0.1".to_string()]);
+        assert_eq!(config.api.query_token_routes,
+                   vec!["/v1/jobs/*".to_string()]);
+        assert_eq!(config.api.max_key_body_size, 8192);
 
         assert_eq!(&format!("{}", config.http.listen), "::1");
 
         assert_eq!(config.memcache.ttl, 11);
+        assert_eq!(&config.memcache.key_prefix, "bldr-a:");
         assert_eq!(&format!("{}", config.memcache.hosts[0]),
                    "memcache://192.168.0.1:12345");
 
         assert_eq!(&format!("{}", config.jobsrv), "http://1.2.3.4:1234");
+        assert_eq!(config.jobsrv.rpc_timeout_secs, 5);
+        assert_eq!(config.jobsrv.rpc_retries, 4);
 
         assert_eq!(config.http.port, 9636);
         assert_eq!(config.http.handler_count, 128);
@@ -459,5 +513,11 @@ mod tests {
 
         let config = Config::from_raw(&content).unwrap();
         assert_eq!(config.http.port, 9000);
+        assert_eq!(config.api.session_duration_secs, 3 * 24 * 60 * 60);
+        assert_eq!(config.api.rate_limit_per_minute, 0);
+        assert_eq!(config.api.max_key_body_size, DEFAULT_MAX_KEY_BODY_SIZE);
+        assert_eq!(&config.memcache.key_prefix, "");
+        assert_eq!(config.jobsrv.rpc_timeout_secs, 30);
+        assert_eq!(config.jobsrv.rpc_retries, 2);
     }
 }
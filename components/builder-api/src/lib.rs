@@ -17,8 +17,6 @@ extern crate bitflags;
 #[macro_use]
 extern crate features;
 #[macro_use]
-extern crate lazy_static;
-#[macro_use]
 extern crate log;
 #[macro_use]
 extern crate serde_derive;
@@ -20,6 +20,7 @@ use artifactory_client::error::ArtifactoryError;
 use github_api_client::HubError;
 use oauth_client::error::Error as OAuthError;
 
+use chrono::NaiveDateTime;
 use rusoto_core::RusotoError;
 
 use std::{fmt,
@@ -59,9 +60,12 @@ pub enum Error {
     PartialUpload(RusotoError<rusoto_s3::UploadPartError>),
     PayloadError(actix_web::error::PayloadError),
     Protobuf(protobuf::ProtobufError),
+    RpcTimeout,
     SerdeJson(serde_json::Error),
     System,
     TLSError(openssl::error::ErrorStack),
+    TokenRevoked(NaiveDateTime),
+    UnknownOAuthProvider(String),
     Unprocessable,
     Utf8(string::FromUtf8Error),
 }
@@ -96,9 +100,12 @@ impl fmt::Display for Error {
             Error::PartialUpload(ref e) => format!("{}", e),
             Error::PayloadError(ref e) => format!("{}", e),
             Error::Protobuf(ref e) => format!("{}", e),
+            Error::RpcTimeout => "Timed out waiting for jobsrv".to_string(),
             Error::SerdeJson(ref e) => format!("{}", e),
             Error::System => "Internal error".to_string(),
             Error::TLSError(ref e) => format!("{}", e),
+            Error::TokenRevoked(ref t) => format!("Access token was revoked at {} UTC", t),
+            Error::UnknownOAuthProvider(ref p) => format!("Unsupported OAuth provider: {}", p),
             Error::Unprocessable => "Unprocessable entity".to_string(),
             Error::Utf8(ref e) => format!("{}", e),
         };
@@ -106,49 +113,48 @@ impl fmt::Display for Error {
     }
 }
 
-impl ResponseError for Error {
-    fn error_response(&self) -> HttpResponse {
+impl Error {
+    /// The HTTP status that this error is reported to clients as
+    pub fn status_code(&self) -> StatusCode {
         match self {
-            Error::Artifactory(ref e) => HttpResponse::new(artifactory_err_to_http(&e)),
-            Error::Authentication => HttpResponse::new(StatusCode::UNAUTHORIZED),
-            Error::Authorization => HttpResponse::new(StatusCode::FORBIDDEN),
-            Error::BadRequest => HttpResponse::new(StatusCode::BAD_REQUEST),
-            Error::Conflict => HttpResponse::new(StatusCode::CONFLICT),
-            Error::Github(_) => HttpResponse::new(StatusCode::FORBIDDEN),
-            Error::NotFound => HttpResponse::new(StatusCode::NOT_FOUND),
-            Error::OAuth(_) => HttpResponse::new(StatusCode::UNAUTHORIZED),
-            Error::DieselError(ref e) => HttpResponse::new(diesel_err_to_http(&e)),
-            Error::System => HttpResponse::new(StatusCode::INTERNAL_SERVER_ERROR),
-            Error::Unprocessable => HttpResponse::new(StatusCode::UNPROCESSABLE_ENTITY),
+            Error::Artifactory(ref e) => artifactory_err_to_http(e),
+            Error::Authentication => StatusCode::UNAUTHORIZED,
+            Error::Authorization => StatusCode::FORBIDDEN,
+            Error::BadRequest => StatusCode::BAD_REQUEST,
+            Error::BuilderCore(ref e) => bldr_core_err_to_http(e),
+            Error::Conflict => StatusCode::CONFLICT,
+            Error::DbError(_) => StatusCode::SERVICE_UNAVAILABLE,
+            Error::DieselError(ref e) => diesel_err_to_http(e),
+            Error::Github(_) => StatusCode::FORBIDDEN,
+            Error::NotFound => StatusCode::NOT_FOUND,
+            Error::OAuth(_) => StatusCode::UNAUTHORIZED,
+            Error::RpcTimeout => StatusCode::GATEWAY_TIMEOUT,
+            Error::System => StatusCode::INTERNAL_SERVER_ERROR,
+            Error::TokenRevoked(_) => StatusCode::UNAUTHORIZED,
+            Error::UnknownOAuthProvider(_) => StatusCode::BAD_REQUEST,
+            Error::Unprocessable => StatusCode::UNPROCESSABLE_ENTITY,
 
             // Default
-            _ => HttpResponse::new(StatusCode::UNPROCESSABLE_ENTITY),
+            _ => StatusCode::UNPROCESSABLE_ENTITY,
         }
     }
 }
 
-impl Into<HttpResponse> for Error {
-    fn into(self) -> HttpResponse {
+impl ResponseError for Error {
+    fn error_response(&self) -> HttpResponse {
         match self {
-            Error::Artifactory(ref e) => HttpResponse::new(artifactory_err_to_http(&e)),
-            Error::Authentication => HttpResponse::new(StatusCode::UNAUTHORIZED),
-            Error::Authorization => HttpResponse::new(StatusCode::FORBIDDEN),
-            Error::BadRequest => HttpResponse::new(StatusCode::BAD_REQUEST),
-            Error::Conflict => HttpResponse::new(StatusCode::CONFLICT),
-            Error::Github(_) => HttpResponse::new(StatusCode::FORBIDDEN),
-            Error::NotFound => HttpResponse::new(StatusCode::NOT_FOUND),
-            Error::OAuth(_) => HttpResponse::new(StatusCode::UNAUTHORIZED),
-            Error::BuilderCore(ref e) => HttpResponse::new(bldr_core_err_to_http(e)),
-            Error::DieselError(ref e) => HttpResponse::new(diesel_err_to_http(e)),
-            Error::System => HttpResponse::new(StatusCode::INTERNAL_SERVER_ERROR),
-            Error::Unprocessable => HttpResponse::new(StatusCode::UNPROCESSABLE_ENTITY),
-
-            // Default
-            _ => HttpResponse::new(StatusCode::UNPROCESSABLE_ENTITY),
+            Error::TokenRevoked(_) | Error::UnknownOAuthProvider(_) => {
+                HttpResponse::build(self.status_code()).body(self.to_string())
+            }
+            _ => HttpResponse::new(self.status_code()),
         }
     }
 }
 
+impl Into<HttpResponse> for Error {
+    fn into(self) -> HttpResponse { self.error_response() }
+}
+
 fn artifactory_err_to_http(err: &ArtifactoryError) -> StatusCode {
     match err {
         ArtifactoryError::ApiError(code, _) => StatusCode::from_u16(code.as_u16()).unwrap(),
@@ -220,6 +226,19 @@ impl From<db::error::Error> for Error {
     fn from(err: db::error::Error) -> Error { Error::DbError(err) }
 }
 
+impl From<db::models::keys::KeyError> for Error {
+    fn from(err: db::models::keys::KeyError) -> Error {
+        match err {
+            db::models::keys::KeyError::DuplicateRevision(_) => Error::Conflict,
+            db::models::keys::KeyError::OwnerNotFound(_) => Error::Unprocessable,
+            db::models::keys::KeyError::OriginNotFound(_) => Error::NotFound,
+            db::models::keys::KeyError::BodyTooLarge(..) => Error::BadRequest,
+            db::models::keys::KeyError::InvalidRange(..) => Error::BadRequest,
+            db::models::keys::KeyError::Database(e) => Error::DieselError(e),
+        }
+    }
+}
+
 impl From<serde_json::Error> for Error {
     fn from(err: serde_json::Error) -> Error { Error::SerdeJson(err) }
 }
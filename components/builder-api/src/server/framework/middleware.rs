@@ -1,4 +1,6 @@
-use crate::{bldr_core::{access_token::{AccessToken,
+use crate::{bldr_core::{self,
+                        access_token::{is_access_token,
+                                       AccessToken,
                                        BUILDER_ACCOUNT_ID,
                                        BUILDER_ACCOUNT_NAME},
                         metrics::CounterMetric,
@@ -10,67 +12,308 @@ use crate::{bldr_core::{access_token::{AccessToken,
                      helpers::req_state,
                      services::metrics::Counter,
                      AppState}};
+use actix_rt::time::{delay_for,
+                     timeout};
 use actix_web::{dev::{Body,
                       Service,
                       ServiceRequest,
                       ServiceResponse},
                 http,
+                web::Query,
                 Error,
                 HttpRequest,
                 HttpResponse};
+use chrono::Utc;
+use diesel::pg::PgConnection;
 use futures::future::{ok,
                       Either,
                       Future};
 use oauth_client::types::OAuth2User;
-use std::env;
+use protobuf::RepeatedField;
+use std::{collections::HashMap,
+          env,
+          net::SocketAddr,
+          time::{Duration,
+                 SystemTime,
+                 UNIX_EPOCH}};
 
-lazy_static! {
-    static ref SESSION_DURATION: u32 = 3 * 24 * 60 * 60;
-}
+// Number of token characters included in authentication failure logs
+const AUDIT_TOKEN_PREFIX_LEN: usize = 8;
+
+// Delay before the first retry of a failed jobsrv RPC
+const RPC_RETRY_BASE_DELAY_MS: u64 = 100;
 
 pub async fn route_message<R, T>(req: &HttpRequest, msg: &R) -> error::Result<T>
     where R: protobuf::Message,
           T: protobuf::Message
 {
     Counter::RouteMessage.increment();
-    // Route via Protobuf over HTTP
-    req_state(req).jobsrv
-                  .rpc::<R, T>(msg)
-                  .await
-                  .map_err(error::Error::BuilderCore)
+    let state = req_state(req);
+    let rpc_timeout = Duration::from_secs(state.config.jobsrv.rpc_timeout_secs);
+    let mut attempt = 0;
+    loop {
+        // Route via Protobuf over HTTP
+        let res = match timeout(rpc_timeout, state.jobsrv.rpc::<R, T>(msg)).await {
+            Ok(res) => res.map_err(error::Error::BuilderCore),
+            Err(_) => {
+                Counter::RouteMessageTimeout.increment();
+                warn!("RPC {} timed out after {:?}",
+                      msg.descriptor().name(),
+                      rpc_timeout);
+                Err(error::Error::RpcTimeout)
+            }
+        };
+        match res {
+            Err(ref err) if attempt < state.config.jobsrv.rpc_retries && rpc_retryable(err) => {
+                attempt += 1;
+                Counter::RouteMessageRetry.increment();
+                let backoff = rpc_backoff(attempt);
+                debug!("Retrying RPC {} in {:?} ({}): {}",
+                       msg.descriptor().name(),
+                       backoff,
+                       attempt,
+                       err);
+                delay_for(backoff).await;
+            }
+            res => return res,
+        }
+    }
+}
+
+// Only failures to connect to jobsrv are retried, since the message was never
+// sent. After a timeout jobsrv may still act on the message, and an error
+// response from jobsrv is a real answer, so neither is retried.
+fn rpc_retryable(err: &error::Error) -> bool {
+    match err {
+        error::Error::BuilderCore(bldr_core::Error::HttpClient(e)) => e.is_connect(),
+        _ => false,
+    }
+}
+
+// Delay before the given retry attempt (starting at 1), doubling each time
+fn rpc_backoff(attempt: u32) -> Duration {
+    Duration::from_millis(RPC_RETRY_BASE_DELAY_MS << (attempt - 1).min(10))
+}
+
+/// Credentials presented in an Authorization header
+#[derive(Debug, PartialEq)]
+pub enum Credentials {
+    /// `Bearer <token>`
+    Bearer(String),
+    /// `Basic base64(<username>:<token>)`, used by machine accounts whose
+    /// clients cannot send Bearer tokens
+    Basic { username: String, token: String },
+}
+
+impl Credentials {
+    /// Parse an Authorization header value. Returns `None` for unknown schemes
+    /// or malformed values. Per RFC 7235 the scheme is case-insensitive, and any
+    /// amount of whitespace may separate it from the credentials.
+    pub fn parse(hdr: &str) -> Option<Credentials> {
+        let hdr_components: Vec<&str> = hdr.split_whitespace().collect();
+        if hdr_components.len() != 2 {
+            return None;
+        }
+
+        match hdr_components[0].to_lowercase().as_ref() {
+            "bearer" => Some(Credentials::Bearer(hdr_components[1].to_string())),
+            "basic" => {
+                let decoded = base64::decode(hdr_components[1]).ok()?;
+                let decoded = String::from_utf8(decoded).ok()?;
+                let mut parts = decoded.splitn(2, ':');
+                let username = parts.next()?;
+                let token = parts.next()?;
+                if username.is_empty() || token.is_empty() {
+                    return None;
+                }
+                Some(Credentials::Basic { username: username.to_string(),
+                                          token:    token.to_string(), })
+            }
+            _ => None,
+        }
+    }
+
+    pub fn token(&self) -> &str {
+        match self {
+            Credentials::Bearer(token) => token,
+            Credentials::Basic { token, .. } => token,
+        }
+    }
 }
 
 // Optional Authentication - this middleware does not enforce authentication,
-// but will insert a Session if a valid Bearer token is received
+// but will insert a Session if a valid Bearer (or Basic) token is received
 pub fn authentication_middleware<S>(mut req: ServiceRequest,
                                     srv: &mut S)
                                     -> impl Future<Output = Result<ServiceResponse<Body>, Error>>
     where S: Service<Request = ServiceRequest, Response = ServiceResponse<Body>, Error = Error>
 {
-    let hdr = match req.headers().get(http::header::AUTHORIZATION) {
-        Some(hdr) => hdr.to_str().unwrap(), // unwrap Ok
-        None => return Either::Left(srv.call(req)),
-    };
+    let state = req.app_data::<AppState>().expect("request state");
+    let ip_bucket = format!("ip:{}", client_ip(&req, &state.config.api.trusted_proxies));
+    let ip_limit = state.config.api.anon_rate_limit_per_minute;
 
-    let hdr_components: Vec<&str> = hdr.split_whitespace().collect();
-    if (hdr_components.len() != 2) || (hdr_components[0] != "Bearer") {
-        return Either::Right(ok(req.into_response(HttpResponse::Unauthorized().finish())));
+    // Requests count against their client IP until they authenticate, so that
+    // guessing tokens is limited along with anonymous requests
+    if let Some(retry) = rate_limited(&state, &ip_bucket, ip_limit) {
+        return Either::Right(ok(req.into_response(too_many_requests(retry))));
     }
-    let token = hdr_components[1];
 
-    let session = match authenticate(&token, &req.app_data().expect("request state")) {
+    let credentials = match req.headers().get(http::header::AUTHORIZATION) {
+        Some(hdr) => {
+            let hdr = hdr.to_str().unwrap(); // unwrap Ok
+            match Credentials::parse(hdr) {
+                Some(credentials) => credentials,
+                None => {
+                    audit_auth_failure(&req, "", "malformed authorization header");
+                    return Either::Right(ok(req.into_response(unauthorized())));
+                }
+            }
+        }
+        None => {
+            match query_token(&req, &state.config.api.query_token_routes) {
+                Some(token) => Credentials::Bearer(token),
+                None => return Either::Left(srv.call(req)),
+            }
+        }
+    };
+
+    let session = match authenticate(credentials.token(), &state) {
         Ok(session) => session,
-        Err(_) => {
-            return Either::Right(ok(req.into_response(HttpResponse::Unauthorized().finish())))
+        Err(err) => {
+            audit_auth_failure(&req, credentials.token(), &err.to_string());
+            return Either::Right(ok(req.into_response(auth_failure_response(&err))));
         }
     };
 
+    // With Basic auth the username must belong to the account the token resolved to
+    if let Credentials::Basic { ref username, .. } = credentials {
+        if session.get_name() != username {
+            audit_auth_failure(&req, credentials.token(), "basic auth username mismatch");
+            return Either::Right(ok(req.into_response(unauthorized())));
+        }
+    }
+
+    // Authenticated requests count against their account instead
+    if ip_limit != 0 {
+        state.memcache.borrow_mut().rate_limit_return(&ip_bucket);
+    }
+    let bucket = format!("account:{}", session.get_id());
+    if let Some(retry) = rate_limited(&state, &bucket, state.config.api.rate_limit_per_minute) {
+        return Either::Right(ok(req.into_response(too_many_requests(retry))));
+    }
+
     req.head_mut()
        .extensions_mut()
        .insert::<originsrv::Session>(session);
     Either::Left(srv.call(req))
 }
 
+// Browsers cannot set headers on WebSocket or EventSource requests, so allowlisted
+// routes may carry the token in an access_token query parameter instead. This is
+// only consulted when no Authorization header is present.
+fn query_token(req: &ServiceRequest, routes: &[String]) -> Option<String> {
+    if !route_allowed(req.path(), routes) {
+        return None;
+    }
+    let query = Query::<HashMap<String, String>>::from_query(req.query_string()).ok()?;
+    query.get("access_token").cloned()
+}
+
+fn route_allowed(path: &str, routes: &[String]) -> bool {
+    routes.iter().any(|route| {
+                     if route.ends_with('*') {
+                         path.starts_with(route.trim_end_matches('*'))
+                     } else {
+                         path == route
+                     }
+                 })
+}
+
+fn unauthorized() -> HttpResponse { HttpResponse::new(error::Error::Authentication.status_code()) }
+
+// Failing to authenticate is always a 401, whatever went wrong while looking the
+// token up, except that an unavailable database is reported as such. Revoked
+// tokens say when they were revoked.
+fn auth_failure_response(err: &error::Error) -> HttpResponse {
+    match err {
+        error::Error::DbError(_) => HttpResponse::new(err.status_code()),
+        error::Error::TokenRevoked(_) => {
+            HttpResponse::build(err.status_code()).body(err.to_string())
+        }
+        _ => unauthorized(),
+    }
+}
+
+// Take a token from the bucket, which holds `limit` tokens and refills at `limit`
+// tokens a minute. If the bucket is empty, return the number of seconds until a
+// token is available again. A limit of zero disables rate limiting, and requests
+// are let through if memcache is unavailable.
+fn rate_limited(state: &AppState, bucket: &str, limit: u32) -> Option<u64> {
+    if limit == 0 {
+        return None;
+    }
+
+    let now_ms = SystemTime::now().duration_since(UNIX_EPOCH)
+                                  .map(|d| d.as_millis() as u64)
+                                  .unwrap_or(0);
+    let empty = state.memcache
+                     .borrow_mut()
+                     .rate_limit_take(bucket, limit, now_ms)?;
+
+    if empty {
+        debug!("Rate limit exceeded: bucket={}, limit={}", bucket, limit);
+        Some(retry_after(limit))
+    } else {
+        None
+    }
+}
+
+// Whole seconds until a bucket refilling at `limit` tokens a minute gains a token
+fn retry_after(limit: u32) -> u64 {
+    let limit = u64::from(limit);
+    (60 + limit - 1) / limit
+}
+
+fn too_many_requests(retry_after: u64) -> HttpResponse {
+    HttpResponse::TooManyRequests().header(http::header::RETRY_AFTER, retry_after.to_string())
+                                   .finish()
+}
+
+// The client IP that anonymous rate limits are keyed on. Forwarding headers are
+// easily forged, so they are only believed when the peer is a trusted proxy.
+fn client_ip(req: &ServiceRequest, trusted_proxies: &[String]) -> String {
+    match req.peer_addr().map(|addr| addr.ip().to_string()) {
+        Some(ref peer) if trusted_proxies.contains(peer) => {
+            remote_ip(req.connection_info().remote().unwrap_or(peer))
+        }
+        Some(peer) => peer,
+        None => "unknown".to_string(),
+    }
+}
+
+// The remote address may include the client port, which must not be part of an
+// IP-based bucket
+fn remote_ip(remote: &str) -> String {
+    match remote.parse::<SocketAddr>() {
+        Ok(addr) => addr.ip().to_string(),
+        Err(_) => remote.to_string(),
+    }
+}
+
+// Record a failed authentication attempt. Only a short prefix of the token is
+// logged so that secrets never end up in the logs.
+fn audit_auth_failure(req: &ServiceRequest, token: &str, reason: &str) {
+    Counter::AuthFailure.increment();
+    warn!("Authentication failed: remote={}, token_prefix={}, reason={}",
+          req.connection_info().remote().unwrap_or("unknown"),
+          token_prefix(token),
+          reason);
+}
+
+/// The start of a token, enough to correlate log lines without logging a
+/// usable credential
+pub fn token_prefix(token: &str) -> String { token.chars().take(AUDIT_TOKEN_PREFIX_LEN).collect() }
+
 fn authenticate(token: &str, state: &AppState) -> error::Result<originsrv::Session> {
     // Test hook - always create a valid session
     if env::var_os("HAB_FUNC_TEST").is_some() {
@@ -79,20 +322,43 @@ fn authenticate(token: &str, state: &AppState) -> error::Result<originsrv::Sessi
         return session_create_short_circuit(token, state);
     };
 
+    // A token that is neither shaped like an access token nor decodes as a
+    // session token can never be valid, so don't spend a cache lookup on it
+    if !is_access_token(token) && decode_token(token).is_err() {
+        trace!("Rejecting malformed token {}", token_prefix(token));
+        return Err(error::Error::Authentication);
+    }
+
     let mut memcache = state.memcache.borrow_mut();
     match memcache.get_session(token) {
         Some(session) => {
-            trace!("Session {} Cache Hit!", token);
+            trace!("Session {} Cache Hit!", token_prefix(token));
+            Counter::SessionCacheHit.increment();
+            // The cache TTL alone is not authoritative, since the entry may outlive
+            // the session if memcache is restored or its TTL is misconfigured
+            if session_expired(token, Utc::now().timestamp()) {
+                trace!("Session {} has expired", token_prefix(token));
+                memcache.delete_session(token);
+                return Err(error::Error::Authentication);
+            }
             Ok(session)
         }
         None => {
-            trace!("Session {} Cache Miss!", token);
+            trace!("Session {} Cache Miss!", token_prefix(token));
+            Counter::SessionCacheMiss.increment();
+
+            // Well-formed tokens that were recently rejected by the database are
+            // remembered briefly so that repeated attempts don't hit the db
+            if memcache.get_invalid_token(token) {
+                trace!("Token {} is cached as invalid", token_prefix(token));
+                return Err(error::Error::Authentication);
+            }
 
             // Pull the session out of the current token provided so we can validate
             // it against the db's tokens
             let mut session = AccessToken::validate_access_token(token, &state.config.api.key_path)
                 .map_err(|_| {
-                    error::Error::Authorization
+                    error::Error::Authentication
                 })?;
 
             if session.get_id() == BUILDER_ACCOUNT_ID {
@@ -108,38 +374,94 @@ fn authenticate(token: &str, state: &AppState) -> error::Result<originsrv::Sessi
 
             match AccountToken::list(session.get_id(), &*conn).map_err(error::Error::DieselError) {
                 Ok(access_tokens) => {
-                    assert!(access_tokens.len() <= 1); // Can only have max of 1 for now
-                    match access_tokens.first() {
-                        Some(access_token) => {
-                            let new_token = access_token.token.clone();
-                            if token.trim_end_matches('=') != new_token.trim_end_matches('=') {
-                                // Token is valid but revoked or otherwise expired
-                                return Err(error::Error::Authorization);
-                            }
+                    if access_tokens.is_empty() {
+                        // We have no tokens in the database for this user
+                        memcache.set_invalid_token(token);
+                        return Err(error::Error::Authentication);
+                    }
 
+                    match find_access_token(token, &access_tokens) {
+                        Ok(access_token) => {
                             let account = Account::get_by_id(session.get_id() as i64, &*conn)
                                 .map_err(error::Error::DieselError)?;
                             session.set_name(account.name);
                             session.set_email(account.email);
+                            session.set_token(access_token.token.clone());
 
-                            memcache.set_session(&new_token, &session, None);
+                            memcache.set_session(&access_token.token, &session, None);
                             Ok(session)
                         }
-                        None => {
-                            // We have no tokens in the database for this user
-                            Err(error::Error::Authorization)
+                        Err(err) => {
+                            // Token is valid but revoked or otherwise expired. Revoked
+                            // tokens are not negatively cached, since the cache would
+                            // answer retries without saying when they were revoked.
+                            if let error::Error::Authentication = err {
+                                memcache.set_invalid_token(token);
+                            }
+                            Err(err)
                         }
                     }
                 }
                 Err(_) => {
                     // Failed to fetch tokens from the database for this user
-                    Err(error::Error::Authorization)
+                    Err(error::Error::Authentication)
                 }
             }
         }
     }
 }
 
+// An account may have several active tokens at once (e.g. while rotating), so
+// accept the presented token if it matches any of them. A match on a revoked
+// token is reported as such rather than as an unknown token.
+fn find_access_token<'a>(token: &str,
+                         access_tokens: &'a [AccountToken])
+                         -> error::Result<&'a AccountToken> {
+    match access_tokens.iter()
+                       .find(|access_token| tokens_match(token, &access_token.token))
+    {
+        Some(access_token) => {
+            match access_token.revoked_at {
+                Some(revoked_at) => Err(error::Error::TokenRevoked(revoked_at)),
+                None => Ok(access_token),
+            }
+        }
+        None => Err(error::Error::Authentication),
+    }
+}
+
+/// Revokes an access token and evicts its cached session, so that the token
+/// stops authenticating immediately rather than when the cache entry expires
+pub fn revoke_token(access_token: &AccountToken,
+                    conn: &PgConnection,
+                    state: &AppState)
+                    -> error::Result<()> {
+    AccountToken::revoke(access_token.id as u64, conn).map_err(error::Error::DieselError)?;
+    state.memcache
+         .borrow_mut()
+         .delete_session(&access_token.token);
+    Ok(())
+}
+
+/// Validate several tokens at once, e.g. for admin tooling. Cached sessions are
+/// fetched from memcache in a single round-trip and the remaining tokens go
+/// through the regular authentication path. Tokens that fail to authenticate are
+/// absent from the result.
+pub fn authenticate_many(tokens: &[&str], state: &AppState) -> HashMap<String, originsrv::Session> {
+    let now = Utc::now().timestamp();
+    let mut sessions = state.memcache.borrow_mut().get_sessions(tokens);
+    sessions.retain(|token, _| !session_expired(token, now));
+
+    for token in tokens {
+        if !sessions.contains_key(*token) {
+            if let Ok(session) = authenticate(token, state) {
+                sessions.insert((*token).to_string(), session);
+            }
+        }
+    }
+    sessions
+}
+
 pub fn session_create_oauth(oauth_token: &str,
                             user: &OAuth2User,
                             provider: &str,
@@ -157,23 +479,36 @@ pub fn session_create_oauth(oauth_token: &str,
         None => "",
     };
 
+    let p = parse_oauth_provider(provider)?;
+    let oauth_provider = provider.to_lowercase();
+    let extern_id = user.id.to_string();
+
     match Account::find_or_create(&NewAccount { name: &user.username,
-                                                email },
+                                                email,
+                                                oauth_provider: Some(&oauth_provider),
+                                                oauth_extern_id: Some(&extern_id) },
                                   &*conn)
     {
         Ok(account) => {
+            // The stored email wins, since the user may have changed it through
+            // their profile. Accounts without one pick up the provider's email.
+            if !account.email.is_empty() {
+                session.set_email(account.email.clone());
+            } else if !email.is_empty() {
+                debug!("Setting email for account {} to {}", account.name, email);
+                if let Err(err) = Account::update_email(account.id as u64, email, &*conn) {
+                    warn!("Failed to set email for account {}: {}", account.name, err);
+                }
+            }
+
             session_token.set_account_id(account.id as u64);
-            session_token.set_extern_id(user.id.to_string());
+            session_token.set_extern_id(extern_id);
             session_token.set_token(oauth_token.to_string().into_bytes());
+            session_token.set_expires(Utc::now().timestamp()
+                                      + i64::from(state.config.api.session_duration_secs));
 
-            match provider.parse::<originsrv::OAuthProvider>() {
-                Ok(p) => session_token.set_provider(p),
-                Err(e) => {
-                    warn!("Error parsing oauth provider: provider={}, err={:?}",
-                          provider, e);
-                    return Err(error::Error::System);
-                }
-            }
+            Counter::SessionCreate(p).increment();
+            session_token.set_provider(p);
 
             let encoded_token = encode_token(&session_token);
             session.set_id(account.id as u64);
@@ -181,20 +516,35 @@ pub fn session_create_oauth(oauth_token: &str,
             session.set_token(encoded_token);
             session.set_flags(FeatureFlags::empty().bits());
             session.set_oauth_token(oauth_token.to_owned());
+            session.set_scopes(RepeatedField::from_vec(user.scopes.clone()));
 
             debug!("issuing session, {:?}", session);
             state.memcache
                  .borrow_mut()
-                 .set_session(&session.get_token(), &session, Some(*SESSION_DURATION));
+                 .set_session(&session.get_token(),
+                              &session,
+                              Some(state.config.api.session_duration_secs));
             Ok(session)
         }
         Err(e) => {
+            Counter::SessionCreateFailure.increment();
             error!("Failed to create session {}", e);
             Err(e.into())
         }
     }
 }
 
+fn parse_oauth_provider(provider: &str) -> error::Result<originsrv::OAuthProvider> {
+    match provider.parse::<originsrv::OAuthProvider>() {
+        Ok(p) => Ok(p),
+        Err(e) => {
+            warn!("Error parsing oauth provider: provider={}, err={:?}",
+                  provider, e);
+            Err(error::Error::UnknownOAuthProvider(provider.to_string()))
+        }
+    }
+}
+
 pub fn session_create_short_circuit(token: &str,
                                     state: &AppState)
                                     -> error::Result<originsrv::Session> {
@@ -202,43 +552,463 @@ pub fn session_create_short_circuit(token: &str,
         "bobo" => {
             (OAuth2User { id:       "0".to_string(),
                           email:    Some("bobo@example.com".to_string()),
-                          username: "bobo".to_string(), },
+                          username: "bobo".to_string(),
+                          scopes:   Vec::new(), },
              "GitHub")
         }
         "mystique" => {
             (OAuth2User { id:       "1".to_string(),
                           email:    Some("mystique@example.com".to_string()),
-                          username: "mystique".to_string(), },
+                          username: "mystique".to_string(),
+                          scopes:   Vec::new(), },
              "GitHub")
         }
         "hank" => {
             (OAuth2User { id:       "2".to_string(),
                           email:    Some("hank@example.com".to_string()),
-                          username: "hank".to_string(), },
+                          username: "hank".to_string(),
+                          scopes:   Vec::new(), },
              "GitHub")
         }
         "wesker" => {
             (OAuth2User { id:       "3".to_string(),
                           email:    Some("awesker@umbrella.corp".to_string()),
-                          username: "wesker".to_string(), },
+                          username: "wesker".to_string(),
+                          scopes:   Vec::new(), },
              "GitHub")
         }
         "lkennedy" => {
             (OAuth2User { id:       "4".to_string(),
                           email:    Some("lkennedy@rcpd.gov".to_string()),
-                          username: "lkennedy".to_string(), },
+                          username: "lkennedy".to_string(),
+                          scopes:   Vec::new(), },
              "GitHub")
         }
         user => {
             error!("Unexpected short circuit token {:?}", user);
-            return Err(error::Error::System);
+            return Err(error::Error::Authentication);
         }
     };
 
     session_create_oauth(token, &user, provider, state)
 }
 
+/// Returns true if the OAuth token behind the session was granted `scope`.
+/// Sessions from access tokens, or from providers that don't report scopes,
+/// have no scopes.
+pub fn session_has_scope(session: &originsrv::Session, scope: &str) -> bool {
+    session.get_scopes().iter().any(|s| s == scope)
+}
+
 fn encode_token(token: &originsrv::SessionToken) -> String {
     let bytes = protocol::message::encode(token).unwrap(); // Unwrap is safe
     base64::encode(&bytes)
 }
+
+/// Decodes a session token as issued by session creation, without checking that
+/// the session is known or unexpired. Anything that is not a well-formed session
+/// token, including personal access tokens, is an `Authentication` error.
+pub fn decode_token(token: &str) -> error::Result<originsrv::SessionToken> {
+    let bytes = base64::decode(token).map_err(|_| error::Error::Authentication)?;
+    protocol::message::decode(&bytes).map_err(|_| error::Error::Authentication)
+}
+
+// Access tokens are not session tokens and carry their own expiry, so only
+// tokens that decode as a SessionToken with an expiry set are considered here.
+fn session_expired(token: &str, now: i64) -> bool {
+    match decode_token(token) {
+        Ok(session_token) => session_token.get_expires() > 0 && session_token.get_expires() <= now,
+        Err(_) => false,
+    }
+}
+
+// Compare two access tokens, ignoring base64 padding, in time that depends only
+// on the token lengths so that the comparison does not leak how many leading
+// bytes of a guessed token were correct.
+pub fn tokens_match(presented: &str, stored: &str) -> bool {
+    let presented = presented.trim_end_matches('=').as_bytes();
+    let stored = stored.trim_end_matches('=').as_bytes();
+
+    if presented.len() != stored.len() {
+        return false;
+    }
+
+    let diff = presented.iter()
+                        .zip(stored.iter())
+                        .fold(0u8, |acc, (a, b)| acc | (a ^ b));
+    diff == 0
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use chrono::NaiveDateTime;
+
+    #[test]
+    fn credentials_parse_bearer() {
+        assert_eq!(Credentials::parse("Bearer abc123"),
+                   Some(Credentials::Bearer("abc123".to_string())));
+    }
+
+    #[test]
+    fn credentials_parse_scheme_is_case_insensitive() {
+        assert_eq!(Credentials::parse("bearer abc123"),
+                   Some(Credentials::Bearer("abc123".to_string())));
+        assert_eq!(Credentials::parse("BEARER abc123"),
+                   Some(Credentials::Bearer("abc123".to_string())));
+    }
+
+    #[test]
+    fn credentials_parse_tolerates_extra_whitespace() {
+        assert_eq!(Credentials::parse("Bearer   abc123 "),
+                   Some(Credentials::Bearer("abc123".to_string())));
+        assert_eq!(Credentials::parse(" Bearer\tabc123"),
+                   Some(Credentials::Bearer("abc123".to_string())));
+        assert_eq!(Credentials::parse("Bearer abc 123"), None);
+    }
+
+    #[test]
+    fn credentials_parse_basic() {
+        let hdr = format!("Basic {}", base64::encode("bobo:abc123"));
+        assert_eq!(Credentials::parse(&hdr),
+                   Some(Credentials::Basic { username: "bobo".to_string(),
+                                             token:    "abc123".to_string(), }));
+    }
+
+    #[test]
+    fn credentials_parse_rejects_malformed() {
+        assert_eq!(Credentials::parse("Bearer"), None);
+        assert_eq!(Credentials::parse("Digest abc123"), None);
+        assert_eq!(Credentials::parse("Basic not-base64!"), None);
+        assert_eq!(Credentials::parse(&format!("Basic {}", base64::encode("bobo"))),
+                   None);
+        assert_eq!(Credentials::parse(&format!("Basic {}", base64::encode(":abc123"))),
+                   None);
+    }
+
+    #[test]
+    fn parse_oauth_provider_rejects_unknown() {
+        match parse_oauth_provider("myspace") {
+            Err(error::Error::UnknownOAuthProvider(p)) => assert_eq!(p, "myspace"),
+            r => panic!("unexpected result: {:?}", r),
+        }
+    }
+
+    #[test]
+    fn decode_token_round_trips_encode_token() {
+        let mut token = originsrv::SessionToken::new();
+        token.set_account_id(42);
+        token.set_extern_id("1234".to_string());
+        token.set_provider(originsrv::OAuthProvider::Bitbucket);
+        token.set_token("oauth-token".to_string().into_bytes());
+        token.set_expires(1_600_000_000);
+
+        let decoded = decode_token(&encode_token(&token)).unwrap();
+        assert_eq!(decoded, token);
+
+        assert!(decode_token("not base64!").is_err());
+        assert!(decode_token("_not-a-session-token").is_err());
+    }
+
+    #[test]
+    fn session_expired_rejects_past_expiry() {
+        let mut session_token = originsrv::SessionToken::new();
+        session_token.set_account_id(1);
+        session_token.set_expires(1000);
+        let token = encode_token(&session_token);
+
+        assert!(session_expired(&token, 1000));
+        assert!(session_expired(&token, 1001));
+        assert!(!session_expired(&token, 999));
+    }
+
+    #[test]
+    fn session_expired_ignores_tokens_without_expiry() {
+        let mut session_token = originsrv::SessionToken::new();
+        session_token.set_account_id(1);
+        let token = encode_token(&session_token);
+
+        assert!(!session_expired(&token, i64::max_value()));
+        assert!(!session_expired("_not-a-session-token", i64::max_value()));
+    }
+
+    #[test]
+    fn rpc_backoff_doubles_each_attempt() {
+        assert_eq!(rpc_backoff(1), Duration::from_millis(100));
+        assert_eq!(rpc_backoff(2), Duration::from_millis(200));
+        assert_eq!(rpc_backoff(3), Duration::from_millis(400));
+    }
+
+    #[test]
+    fn rpc_retryable_skips_timeouts_and_protocol_errors() {
+        assert!(!rpc_retryable(&error::Error::RpcTimeout));
+        assert!(!rpc_retryable(&error::Error::BuilderCore(
+            bldr_core::Error::RpcError(404, "not found".to_string())
+        )));
+        assert!(!rpc_retryable(&error::Error::NotFound));
+    }
+
+    #[test]
+    fn retry_after_waits_for_one_token() {
+        assert_eq!(retry_after(1), 60);
+        assert_eq!(retry_after(7), 9);
+        assert_eq!(retry_after(60), 1);
+        assert_eq!(retry_after(600), 1);
+    }
+
+    #[test]
+    fn client_ip_only_trusts_forwarding_from_proxies() {
+        let req = |peer: &str| {
+            actix_web::test::TestRequest::default().peer_addr(peer.parse().unwrap())
+                                                   .header("X-Forwarded-For", "192.0.2.1")
+                                                   .to_srv_request()
+        };
+        let proxies = vec!["10.0.0.1".to_string()];
+
+        assert_eq!(client_ip(&req("10.0.0.1:52134"), &proxies), "192.0.2.1");
+        assert_eq!(client_ip(&req("10.0.0.2:52134"), &proxies), "10.0.0.2");
+        assert_eq!(client_ip(&req("10.0.0.1:52134"), &[]), "10.0.0.1");
+    }
+
+    #[test]
+    fn remote_ip_strips_port() {
+        assert_eq!(remote_ip("10.0.0.1:52134"), "10.0.0.1");
+        assert_eq!(remote_ip("[::1]:52134"), "::1");
+        assert_eq!(remote_ip("10.0.0.1"), "10.0.0.1");
+    }
+
+    #[test]
+    fn route_allowed_matches_exact_and_prefix_routes() {
+        let routes = vec!["/v1/status".to_string(), "/v1/jobs/*".to_string()];
+
+        assert!(route_allowed("/v1/status", &routes));
+        assert!(route_allowed("/v1/jobs/1234/log", &routes));
+        assert!(!route_allowed("/v1/status/extra", &routes));
+        assert!(!route_allowed("/v1/profile", &routes));
+        assert!(!route_allowed("/v1/status", &[]));
+    }
+
+    #[test]
+    fn session_has_scope_matches_granted_scopes() {
+        let mut session = originsrv::Session::new();
+        assert!(!session_has_scope(&session, "read:org"));
+
+        session.set_scopes(RepeatedField::from_vec(vec!["user:email".to_string(),
+                                                        "read:org".to_string()]));
+        assert!(session_has_scope(&session, "read:org"));
+        assert!(!session_has_scope(&session, "read"));
+    }
+
+    fn access_token(token: &str, revoked_at: Option<NaiveDateTime>) -> AccountToken {
+        AccountToken { id: 1,
+                       account_id: 2,
+                       token: token.to_string(),
+                       created_at: None,
+                       revoked_at }
+    }
+
+    #[test]
+    fn find_access_token_reports_revoked_tokens() {
+        let revoked_at = NaiveDateTime::from_timestamp(1_600_000_000, 0);
+        let tokens = vec![access_token("_active", None),
+                          access_token("_revoked", Some(revoked_at))];
+
+        assert_eq!(find_access_token("_active", &tokens).unwrap().token,
+                   "_active");
+        match find_access_token("_revoked", &tokens) {
+            Err(error::Error::TokenRevoked(t)) => assert_eq!(t, revoked_at),
+            res => panic!("unexpected result {:?}", res),
+        }
+        match find_access_token("_unknown", &tokens) {
+            Err(error::Error::Authentication) => (),
+            res => panic!("unexpected result {:?}", res),
+        }
+    }
+
+    #[test]
+    fn auth_failure_response_is_unauthorized() {
+        let resp =
+            auth_failure_response(&error::Error::DieselError(diesel::result::Error::NotFound));
+        assert_eq!(resp.status(), http::StatusCode::UNAUTHORIZED);
+
+        let resp = auth_failure_response(&error::Error::Authentication);
+        assert_eq!(resp.status(), http::StatusCode::UNAUTHORIZED);
+    }
+
+    #[test]
+    fn auth_failure_response_reports_revocation_time() {
+        let revoked_at = NaiveDateTime::from_timestamp(1_600_000_000, 0);
+        let resp = auth_failure_response(&error::Error::TokenRevoked(revoked_at));
+        assert_eq!(resp.status(), http::StatusCode::UNAUTHORIZED);
+        match resp.body().as_ref() {
+            Some(Body::Bytes(body)) => {
+                assert_eq!(&body[..],
+                           &b"Access token was revoked at 2020-09-13 12:26:40 UTC"[..])
+            }
+            _ => panic!("expected a response body"),
+        }
+    }
+
+    #[test]
+    fn token_prefix_truncates() {
+        assert_eq!(token_prefix("_Qk9YLTEKYmxkci0yMDIwMDkx"), "_Qk9YLTE");
+        assert_eq!(token_prefix("bobo"), "bobo");
+        assert_eq!(token_prefix(""), "");
+    }
+
+    #[test]
+    fn tokens_match_ignores_padding() {
+        assert!(tokens_match("abcdef==", "abcdef"));
+        assert!(tokens_match("abcdef", "abcdef="));
+    }
+
+    #[test]
+    fn tokens_match_rejects_last_byte_difference() {
+        assert!(!tokens_match("abcdef", "abcdeg"));
+        assert!(!tokens_match("abcdef==", "abcdeg=="));
+    }
+
+    #[test]
+    fn tokens_match_rejects_length_difference() {
+        assert!(!tokens_match("abcdef", "abcde"));
+        assert!(!tokens_match("", "a"));
+    }
+}
+
+#[cfg(test)]
+#[cfg(feature = "postgres_tests")]
+// cargo test -p habitat_builder_api --features=postgres_tests
+// These also need memcached listening on localhost:11211
+mod postgres_test {
+    use super::*;
+    use crate::{config::Config,
+                db::{diesel_pool::DbPool,
+                     pool::Pool},
+                hab_core::crypto::keys::{generate_builder_encryption_key,
+                                         KeyCache}};
+    use habitat_builder_db::datastore_test;
+    use tempfile::TempDir;
+
+    struct TestDataStore {
+        diesel_pool: DbPool,
+    }
+
+    impl TestDataStore {
+        fn from_pool(_pool: Pool, diesel_pool: DbPool) -> Self { TestDataStore { diesel_pool } }
+
+        // The API only needs the builder-db migrations, which datastore_test!
+        // runs itself
+        fn setup(&self) -> Result<(), ()> { Ok(()) }
+    }
+
+    // Application state backed by a fresh database and a key cache holding a new
+    // Builder key. Memcache keys get a per-test prefix so tests can't see each
+    // other's entries.
+    fn test_state() -> (AppState, TempDir) {
+        let ds = datastore_test!(TestDataStore);
+        let dir = tempfile::Builder::new().prefix("key_cache")
+                                          .tempdir()
+                                          .unwrap();
+        let mut config = Config::default();
+        config.api.key_path = KeyCache::new(dir.path());
+        config.api.key_path.setup().unwrap();
+        config.api
+              .key_path
+              .write_key(&generate_builder_encryption_key())
+              .unwrap();
+        config.memcache.key_prefix = format!("{}:", uuid::Uuid::new_v4());
+        (AppState::new(&config, ds.diesel_pool).unwrap(), dir)
+    }
+
+    fn create_account(state: &AppState) -> Account {
+        let conn = state.db.get_conn().unwrap();
+        Account::create(&NewAccount { name:            "bobo",
+                                      email:           "bobo@example.com",
+                                      oauth_provider:  None,
+                                      oauth_extern_id: None, },
+                        &*conn).unwrap()
+    }
+
+    fn user_token(state: &AppState, account: &Account) -> String {
+        AccessToken::user_token(&state.config.api.key_path,
+                                account.id as u64,
+                                FeatureFlags::empty().bits()).unwrap()
+                                                             .to_string()
+    }
+
+    fn create_token(state: &AppState, account: &Account, token: &str) -> AccountToken {
+        let conn = state.db.get_conn().unwrap();
+        AccountToken::create(&NewAccountToken { account_id: account.id,
+                                                token },
+                             &*conn).unwrap()
+    }
+
+    #[test]
+    fn revoked_token_is_evicted_and_rejected() {
+        let (state, _dir) = test_state();
+        let account = create_account(&state);
+        let token = user_token(&state, &account);
+        let access_token = create_token(&state, &account, &token);
+
+        assert_eq!(authenticate(&token, &state).unwrap().get_id(),
+                   account.id as u64);
+        assert!(state.memcache.borrow_mut().get_session(&token).is_some());
+
+        let conn = state.db.get_conn().unwrap();
+        revoke_token(&access_token, &*conn, &state).unwrap();
+        assert!(state.memcache.borrow_mut().get_session(&token).is_none());
+
+        match authenticate(&token, &state) {
+            Err(error::Error::TokenRevoked(_)) => (),
+            res => panic!("unexpected result {:?}", res),
+        }
+        // The rejected token must not be cached again, as a session or as invalid
+        assert!(state.memcache.borrow_mut().get_session(&token).is_none());
+        assert!(!state.memcache.borrow_mut().get_invalid_token(&token));
+    }
+
+    #[test]
+    fn invalid_token_is_answered_from_the_cache() {
+        let (state, _dir) = test_state();
+        let account = create_account(&state);
+        let token = user_token(&state, &account);
+
+        match authenticate(&token, &state) {
+            Err(error::Error::Authentication) => (),
+            res => panic!("unexpected result {:?}", res),
+        }
+        assert!(state.memcache.borrow_mut().get_invalid_token(&token));
+
+        // Created behind the cache's back, so only a db lookup would find it
+        create_token(&state, &account, &token);
+        match authenticate(&token, &state) {
+            Err(error::Error::Authentication) => (),
+            res => panic!("unexpected result {:?}", res),
+        }
+
+        state.memcache.borrow_mut().delete_invalid_token(&token);
+        assert_eq!(authenticate(&token, &state).unwrap().get_id(),
+                   account.id as u64);
+    }
+
+    #[test]
+    fn expired_session_is_rejected_despite_the_cache() {
+        let (state, _dir) = test_state();
+        let mut session_token = originsrv::SessionToken::new();
+        session_token.set_account_id(1);
+        session_token.set_expires(Utc::now().timestamp() - 1);
+        let token = encode_token(&session_token);
+        let mut session = originsrv::Session::new();
+        session.set_id(1);
+        session.set_token(token.clone());
+        state.memcache
+             .borrow_mut()
+             .set_session(&token, &session, None);
+
+        match authenticate(&token, &state) {
+            Err(error::Error::Authentication) => (),
+            res => panic!("unexpected result {:?}", res),
+        }
+        assert!(state.memcache.borrow_mut().get_session(&token).is_none());
+    }
+}
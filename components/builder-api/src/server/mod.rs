@@ -27,11 +27,14 @@ use crate::{bldr_core::rpc::RpcClient,
                  DbPool}};
 use actix_web::{http::StatusCode,
                 middleware::Logger,
-                web,
+                web::{self,
+                      Data},
                 App,
                 HttpResponse,
                 HttpServer};
 use artifactory_client::client::ArtifactoryClient;
+use diesel::{Connection,
+             RunQueryDsl};
 use github_api_client::GitHubClient;
 use oauth_client::client::OAuth2Client;
 use openssl::ssl::{SslAcceptor,
@@ -43,7 +46,15 @@ use rand::{self,
 use std::{cell::RefCell,
           collections::HashMap,
           iter::FromIterator,
-          sync::Arc};
+          sync::Arc,
+          time::Duration};
+
+// Upper bound on how long the health check waits on each dependency
+const HEALTH_CHECK_TIMEOUT_MS: u64 = 2000;
+
+// The default access log format with the request line replaced by the path, so
+// that query strings are not logged
+const ACCESS_LOG_FORMAT_NO_QUERY: &str = r#"%a "%U" %s %b "%{Referer}i" "%{User-Agent}i" %T"#;
 
 // This cipher list corresponds to the "intermediate" configuration
 // recommended by Mozilla:
@@ -122,6 +133,61 @@ fn enable_features(config: &Config) {
 /// Returns a status 200 on success. Any non-200 responses are an outage or a partial outage.
 pub fn status() -> HttpResponse { HttpResponse::new(StatusCode::OK) }
 
+#[derive(Serialize)]
+struct HealthStatus {
+    db:       bool,
+    memcache: bool,
+}
+
+/// Endpoint for load balancer probes that verifies the datastore and memcache are reachable.
+///
+/// Returns a status 200 when both are healthy and a 503 otherwise.
+#[allow(clippy::needless_pass_by_value)]
+pub fn health(state: Data<AppState>) -> HttpResponse {
+    let timeout = Duration::from_millis(HEALTH_CHECK_TIMEOUT_MS);
+
+    let db = match state.db.get_conn_timeout(timeout) {
+        Ok(conn) => {
+            let result = conn.transaction::<_, diesel::result::Error, _>(|| {
+                                 diesel::sql_query(format!("SET LOCAL statement_timeout = {}",
+                                                           HEALTH_CHECK_TIMEOUT_MS))
+                                     .execute(&*conn)?;
+                                 diesel::sql_query("SELECT 1").execute(&*conn)
+                             });
+            match result {
+                Ok(_) => true,
+                Err(e) => {
+                    warn!("Datastore health check failed: {}", e);
+                    false
+                }
+            }
+        }
+        Err(e) => {
+            warn!("Unable to get datastore connection for health check: {}", e);
+            false
+        }
+    };
+    let memcache = state.memcache.borrow_mut().healthy(timeout);
+
+    let status = HealthStatus { db, memcache };
+    if status.db && status.memcache {
+        HttpResponse::Ok().json(status)
+    } else {
+        HttpResponse::ServiceUnavailable().json(status)
+    }
+}
+
+// Routes in query_token_routes take an access_token query parameter, and the
+// default request line would write it to the access log
+fn access_logger(config: &Config) -> Logger {
+    let logger = if config.api.query_token_routes.is_empty() {
+        Logger::default()
+    } else {
+        Logger::new(ACCESS_LOG_FORMAT_NO_QUERY)
+    };
+    logger.exclude("/v1/status").exclude("/v1/health")
+}
+
 pub async fn run(config: Config) -> error::Result<()> {
     enable_features(&config);
 
@@ -144,7 +210,7 @@ pub async fn run(config: Config) -> error::Result<()> {
                       App::new()
             .data(app_state)
             .wrap_fn(authentication_middleware)
-            .wrap(Logger::default().exclude("/v1/status"))
+            .wrap(access_logger(&config))
             .service(
                 web::scope("/v1")
                     .configure(Authenticate::register)
@@ -162,6 +228,11 @@ pub async fn run(config: Config) -> error::Result<()> {
                         web::resource("/status")
                             .route(web::get().to(status))
                             .route(web::head().to(status)),
+                    )
+                    .service(
+                        web::resource("/health")
+                            .route(web::get().to(health))
+                            .route(web::head().to(health)),
                     ),
             )
                   }).workers(cfg.handler_count())
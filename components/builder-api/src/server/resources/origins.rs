@@ -786,7 +786,7 @@ fn download_latest_origin_secret_key(req: HttpRequest,
         Err(err) => return err.into(),
     };
 
-    let key = match OriginPrivateSigningKey::get(&origin, &*conn).map_err(Error::DieselError) {
+    let key = match OriginPrivateSigningKey::latest(&origin, &*conn).map_err(Error::DieselError) {
         Ok(key) => key,
         Err(err) => {
             debug!("{}", err);
@@ -884,7 +884,11 @@ fn download_latest_origin_encryption_key(req: HttpRequest,
         Ok(key) => key,
         Err(NotFound) => {
             // TODO: redesign to not be generating keys during d/l
-            match generate_origin_encryption_keys(&origin, account_id, &conn) {
+            match generate_origin_encryption_keys(&origin,
+                                                  account_id,
+                                                  state.config.api.max_key_body_size,
+                                                  &conn)
+            {
                 Ok(key) => key,
                 Err(err) => {
                     debug!("{}", err);
@@ -1589,6 +1593,7 @@ fn download_content_as_file(content: String, filename: String) -> HttpResponse {
 
 fn generate_origin_encryption_keys(origin: &str,
                                    session_id: u64,
+                                   max_body_size: usize,
                                    conn: &PgConnection)
                                    -> Result<OriginPublicEncryptionKey> {
     debug!("Generating encryption keys for {}", origin);
@@ -1610,8 +1615,10 @@ fn generate_origin_encryption_keys(origin: &str,
                                                  revision:  &secret.named_revision().revision(),
                                                  body:      sk_body.as_ref(), };
 
-    OriginPrivateEncryptionKey::create(&new_sk, &*conn)?;
-    Ok(OriginPublicEncryptionKey::create(&new_pk, &*conn)?)
+    OriginPrivateEncryptionKey::create(&new_sk, max_body_size, &*conn)?;
+    Ok(OriginPublicEncryptionKey::create(&new_pk,
+                                         max_body_size,
+                                         &*conn)?)
 }
 
 fn save_public_origin_signing_key(account_id: u64,
@@ -4,7 +4,9 @@ use crate::{bldr_core,
             server::{authorize::authorize_session,
                      error::{Error,
                              Result},
-                     framework::headers,
+                     framework::{headers,
+                                 middleware::{revoke_token,
+                                              tokens_match}},
                      helpers::req_state,
                      AppState}};
 use actix_web::{http::{self,
@@ -36,7 +38,9 @@ impl Profile {
            .route("/profile/access-tokens",
                   web::post().to(generate_access_token))
            .route("/profile/access-tokens/{id}",
-                  web::delete().to(revoke_access_token));
+                  web::delete().to(revoke_access_token))
+           .route("/profile/tokens",
+                  web::delete().to(revoke_current_access_token));
     }
 }
 
@@ -44,7 +48,10 @@ impl Profile {
 pub fn do_get_access_tokens(req: &HttpRequest, account_id: u64) -> Result<Vec<AccountToken>> {
     let conn = req_state(req).db.get_conn().map_err(Error::DbError)?;
 
-    AccountToken::list(account_id, &*conn).map_err(Error::DieselError)
+    let access_tokens = AccountToken::list(account_id, &*conn).map_err(Error::DieselError)?;
+    Ok(access_tokens.into_iter()
+                    .filter(|t| !t.is_revoked())
+                    .collect())
 }
 
 // Route handlers - these functions can return any Responder trait
@@ -105,16 +112,6 @@ fn generate_access_token(req: HttpRequest, state: Data<AppState>) -> HttpRespons
         Err(err) => return err.into(),
     };
 
-    // Memcache supports multiple tokens but to preserve legacy behavior
-    // we must purge any existing tokens AFTER generating new ones
-    let access_tokens = match AccountToken::list(account_id, &*conn).map_err(Error::DieselError) {
-        Ok(access_tokens) => access_tokens,
-        Err(err) => {
-            debug!("{}", err);
-            return err.into();
-        }
-    };
-
     // TODO: Provide an API for this
     let flags = {
         let extension = req.extensions();
@@ -130,14 +127,30 @@ fn generate_access_token(req: HttpRequest, state: Data<AppState>) -> HttpRespons
         }
     };
 
+    let previous_tokens = match AccountToken::list(account_id, &*conn).map_err(Error::DieselError) {
+        Ok(previous_tokens) => previous_tokens,
+        Err(err) => {
+            debug!("{}", err);
+            return err.into();
+        }
+    };
+
     let new_token = NewAccountToken { account_id: account_id as i64,
                                       token:      &token, };
 
     match AccountToken::create(&new_token, &*conn).map_err(Error::DieselError) {
         Ok(account_token) => {
-            let mut memcache = state.memcache.borrow_mut();
-            for token in access_tokens {
-                memcache.delete_session_key(&token.token)
+            state.memcache
+                 .borrow_mut()
+                 .delete_invalid_token(&account_token.token);
+
+            // The new token replaces any earlier ones, so a leaked token stops
+            // working once it is regenerated
+            for previous in previous_tokens.iter().filter(|t| !t.is_revoked()) {
+                if let Err(err) = revoke_token(previous, &*conn, &state) {
+                    debug!("{}", err);
+                    return err.into();
+                }
             }
             HttpResponse::Ok().json(account_token)
         }
@@ -177,14 +190,59 @@ fn revoke_access_token(req: HttpRequest,
         }
     };
 
-    match AccountToken::delete(token_id, &*conn).map_err(Error::DieselError) {
-        Ok(_) => {
-            let mut memcache = state.memcache.borrow_mut();
-            for token in access_tokens {
-                memcache.delete_session_key(&token.token)
-            }
-            HttpResponse::Ok().finish()
+    // Only allow revoking tokens that belong to the caller
+    let access_token = match access_tokens.into_iter()
+                                          .find(|t| t.id == token_id as i64 && !t.is_revoked())
+    {
+        Some(access_token) => access_token,
+        None => return HttpResponse::new(StatusCode::NOT_FOUND),
+    };
+
+    match revoke_token(&access_token, &*conn, &state) {
+        Ok(_) => HttpResponse::Ok().finish(),
+        Err(err) => {
+            debug!("{}", err);
+            err.into()
         }
+    }
+}
+
+// Revokes the access token that was used to authenticate this request
+#[allow(clippy::needless_pass_by_value)]
+fn revoke_current_access_token(req: HttpRequest, state: Data<AppState>) -> HttpResponse {
+    let session = match authorize_session(&req, None, None) {
+        Ok(session) => session,
+        Err(err) => return err.into(),
+    };
+    let account_id = session.get_id();
+
+    // Sessions created via OAuth carry a session token, which matches none of
+    // the account's access tokens
+    let token = session.get_token();
+
+    let conn = match state.db.get_conn().map_err(Error::DbError) {
+        Ok(conn_ref) => conn_ref,
+        Err(err) => return err.into(),
+    };
+
+    let access_tokens = match AccountToken::list(account_id, &*conn).map_err(Error::DieselError) {
+        Ok(access_tokens) => access_tokens,
+        Err(err) => {
+            debug!("{}", err);
+            return err.into();
+        }
+    };
+
+    let access_token =
+        match access_tokens.into_iter()
+                           .find(|t| tokens_match(token, &t.token) && !t.is_revoked())
+        {
+            Some(access_token) => access_token,
+            None => return HttpResponse::new(StatusCode::NOT_FOUND),
+        };
+
+    match revoke_token(&access_token, &*conn, &state) {
+        Ok(_) => HttpResponse::new(StatusCode::NO_CONTENT),
         Err(err) => {
             debug!("{}", err);
             err.into()
@@ -211,7 +269,7 @@ fn update_account(req: HttpRequest,
         Err(err) => return err.into(),
     };
 
-    match Account::update(account_id, &body.email, &*conn).map_err(Error::DieselError) {
+    match Account::update_email(account_id, &body.email, &*conn).map_err(Error::DieselError) {
         Ok(_) => HttpResponse::new(StatusCode::OK),
         Err(err) => {
             debug!("{}", err);
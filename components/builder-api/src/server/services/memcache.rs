@@ -18,7 +18,9 @@ use rand::{self,
            Rng};
 use sha2::{Digest,
            Sha512};
-use std::time::Instant;
+use std::{collections::HashMap,
+          time::{Duration,
+                 Instant}};
 
 use super::metrics::Histogram;
 
@@ -26,11 +28,20 @@ use crate::{bldr_core::metrics::HistogramMetric,
             config::MemcacheCfg,
             hab_core::{package::PackageIdent,
                        ChannelIdent},
-            protocol::originsrv::Session};
+            protocol::originsrv::Session,
+            server::framework::middleware::token_prefix};
+
+// Seconds to remember that a well-formed token was rejected by the database
+const INVALID_TOKEN_TTL: u32 = 30;
+
+// Seconds for an empty rate limit bucket to refill completely. Bucket state that
+// has not changed for this long describes a full bucket, so it is left to expire.
+const RATE_LIMIT_REFILL_SECS: u32 = 60;
 
 pub struct MemcacheClient {
-    cli: memcache::Client,
-    ttl: u32,
+    cli:        memcache::Client,
+    ttl:        u32,
+    key_prefix: String,
 }
 
 impl MemcacheClient {
@@ -38,8 +49,29 @@ impl MemcacheClient {
         trace!("Creating memcache client, hosts: {:?}", config.hosts);
         let memcache_host_strings = config.memcache_hosts();
         let memcache_hosts: Vec<&str> = memcache_host_strings.iter().map(AsRef::as_ref).collect();
-        MemcacheClient { cli: memcache::Client::connect(memcache_hosts).unwrap(),
-                         ttl: config.ttl, }
+        MemcacheClient { cli:        memcache::Client::connect(memcache_hosts).unwrap(),
+                         ttl:        config.ttl,
+                         key_prefix: config.key_prefix.clone(), }
+    }
+
+    /// Returns true if the memcache servers answer a version request within the timeout
+    pub fn healthy(&mut self, timeout: Duration) -> bool {
+        if let Err(e) = self.cli.set_read_timeout(Some(timeout)) {
+            warn!("Unable to set memcache read timeout: {}", e);
+            return false;
+        }
+        let result = self.cli.version();
+        if let Err(e) = self.cli.set_read_timeout(None) {
+            warn!("Unable to reset memcache read timeout: {}", e);
+        }
+
+        match result {
+            Ok(_) => true,
+            Err(e) => {
+                warn!("Memcache health check failed: {}", e);
+                false
+            }
+        }
     }
 
     pub fn set_package(&mut self,
@@ -144,20 +176,68 @@ impl MemcacheClient {
     }
 
     pub fn get_session(&mut self, token: &str) -> Option<Session> {
-        trace!("Getting session for token {} from memcached", token);
+        trace!("Getting session for token {} from memcached",
+               token_prefix(token));
 
         let start_time = Instant::now();
-        match self.get_bytes(&hash_key(token)) {
+        let key = session_key(&self.key_prefix, token);
+        match self.get_bytes(&key) {
             Some(session) => {
                 let duration_millis = start_time.elapsed().as_millis();
                 trace!("Memcache get_session time: {} ms", duration_millis);
                 Histogram::MemcacheCallTime.set(duration_millis as f64);
-                Some(protobuf::parse_from_bytes(&session).unwrap())
+                // A corrupt entry is treated as a cache miss so the caller falls
+                // back to validating the token against the database
+                match protobuf::parse_from_bytes(&session) {
+                    Ok(session) => Some(session),
+                    Err(e) => {
+                        warn!("Unable to parse cached session {}: {}", key, e);
+                        None
+                    }
+                }
             }
             None => None,
         }
     }
 
+    /// Fetch the cached sessions for several tokens in one round-trip. Tokens with
+    /// no cached session are absent from the result. If the servers reject the
+    /// multi-get, each token is fetched individually instead.
+    pub fn get_sessions(&mut self, tokens: &[&str]) -> HashMap<String, Session> {
+        trace!("Getting sessions for {} tokens from memcached",
+               tokens.len());
+
+        let keys: HashMap<String, &str> = tokens.iter()
+                                                .map(|t| (session_key(&self.key_prefix, t), *t))
+                                                .collect();
+        let key_refs: Vec<&str> = keys.keys().map(String::as_str).collect();
+
+        let start_time = Instant::now();
+        let sessions = match self.cli.gets::<Vec<u8>>(&key_refs) {
+            Ok(found) => {
+                found.into_iter()
+                     .filter_map(|(key, bytes)| {
+                         let token = keys.get(&key)?;
+                         let session = protobuf::parse_from_bytes(&bytes).ok()?;
+                         Some((token.to_string(), session))
+                     })
+                     .collect()
+            }
+            Err(e) => {
+                debug!("Memcache multi-get failed, falling back to single gets: {}",
+                       e);
+                tokens.iter()
+                      .filter_map(|t| self.get_session(t).map(|s| (t.to_string(), s)))
+                      .collect()
+            }
+        };
+        let duration_millis = start_time.elapsed().as_millis();
+        trace!("Memcache get_sessions time: {} ms", duration_millis);
+        Histogram::MemcacheCallTime.set(duration_millis as f64);
+
+        sessions
+    }
+
     pub fn delete_role_key(&mut self, key: &str) {
         match self.cli.delete(key) {
             Ok(b) => {
@@ -171,10 +251,11 @@ impl MemcacheClient {
         };
     }
 
-    pub fn delete_session_key(&mut self, key: &str) {
-        match self.cli.delete(&hash_key(key)) {
-            Ok(b) => debug!("Deleted key {}, {:?}", key, b),
-            Err(e) => debug!("Failed to delete key {}: {}", key, e),
+    pub fn delete_session(&mut self, token: &str) {
+        let key = session_key(&self.key_prefix, token);
+        match self.cli.delete(&key) {
+            Ok(b) => debug!("Deleted session {}, {:?}", key, b),
+            Err(e) => debug!("Failed to delete session {}: {}", key, e),
         };
     }
 
@@ -184,7 +265,7 @@ impl MemcacheClient {
             None => self.ttl * 60,
         };
 
-        match self.cli.set(&hash_key(token),
+        match self.cli.set(&session_key(&self.key_prefix, token),
                            session.write_to_bytes().unwrap().as_slice(),
                            computed_ttl)
         {
@@ -193,6 +274,99 @@ impl MemcacheClient {
         };
     }
 
+    pub fn set_invalid_token(&mut self, token: &str) {
+        match self.cli.set(&invalid_token_key(&self.key_prefix, token),
+                           true,
+                           INVALID_TOKEN_TTL)
+        {
+            Ok(_) => trace!("Saved invalid token to memcached!"),
+            Err(e) => warn!("Failed to save invalid token to memcached: {}", e),
+        };
+    }
+
+    pub fn get_invalid_token(&mut self, token: &str) -> bool {
+        let key = invalid_token_key(&self.key_prefix, token);
+        trace!("Getting invalid token {} from memcached", key);
+
+        let start_time = Instant::now();
+        let ret = self.get_bool(&key);
+        let duration_millis = start_time.elapsed().as_millis();
+        trace!("Memcache get_invalid_token time: {} ms", duration_millis);
+        Histogram::MemcacheCallTime.set(duration_millis as f64);
+
+        ret.unwrap_or(false)
+    }
+
+    pub fn delete_invalid_token(&mut self, token: &str) {
+        let key = invalid_token_key(&self.key_prefix, token);
+        match self.cli.delete(&key) {
+            Ok(b) => debug!("Deleted invalid token {}, {:?}", key, b),
+            Err(e) => debug!("Failed to delete invalid token {}: {}", key, e),
+        };
+    }
+
+    /// Take a token from a rate limit bucket that holds up to `capacity` tokens
+    /// and refills at `capacity` tokens a minute. Returns true if the bucket was
+    /// empty, in which case no token is taken. Returns None if the bucket could
+    /// not be updated, in which case callers should not limit the request.
+    pub fn rate_limit_take(&mut self, bucket: &str, capacity: u32, now_ms: u64) -> Option<bool> {
+        let used_key = rate_limit_key(&self.key_prefix, bucket, "used");
+        let refill_key = rate_limit_key(&self.key_prefix, bucket, "refill");
+
+        match self.get_string(&refill_key)
+                  .and_then(|v| v.parse::<u64>().ok())
+        {
+            Some(refilled_at) => {
+                let (tokens, next_refilled_at) = bucket_refill(refilled_at, now_ms, capacity);
+                // Only the first request to see this refill time adds the tokens,
+                // so concurrent requests don't add the same tokens twice
+                let claim_key = format!("{}:{}", refill_key, refilled_at);
+                if tokens > 0
+                   && self.cli
+                          .add(&claim_key, 1u64, RATE_LIMIT_REFILL_SECS)
+                          .is_ok()
+                {
+                    let used = self.get_string(&used_key)
+                                   .and_then(|v| v.parse::<u64>().ok())
+                                   .unwrap_or(0);
+                    let _ = self.cli.set(&used_key,
+                                         used.saturating_sub(tokens),
+                                         RATE_LIMIT_REFILL_SECS);
+                    let _ = self.cli
+                                .set(&refill_key, next_refilled_at, RATE_LIMIT_REFILL_SECS);
+                }
+            }
+            None => {
+                let _ = self.cli.set(&refill_key, now_ms, RATE_LIMIT_REFILL_SECS);
+            }
+        }
+
+        // Add fails harmlessly when the bucket already exists
+        let _ = self.cli.add(&used_key, 0u64, RATE_LIMIT_REFILL_SECS);
+        match self.cli.increment(&used_key, 1) {
+            Ok(used) if used > u64::from(capacity) => {
+                // Requests that are turned away don't use up a token
+                let _ = self.cli.decrement(&used_key, 1);
+                Some(true)
+            }
+            Ok(_) => Some(false),
+            Err(e) => {
+                warn!("Failed to take a token from rate limit bucket {}: {}",
+                      bucket, e);
+                None
+            }
+        }
+    }
+
+    /// Put back a token taken from a rate limit bucket with `rate_limit_take`
+    pub fn rate_limit_return(&mut self, bucket: &str) {
+        let used_key = rate_limit_key(&self.key_prefix, bucket, "used");
+        if let Err(e) = self.cli.decrement(&used_key, 1) {
+            debug!("Failed to return a token to rate limit bucket {}: {}",
+                   bucket, e);
+        }
+    }
+
     pub fn set_origin_member(&mut self, origin: &str, account_id: u64, val: bool) {
         let key = format!("member:{}/{}", origin, account_id);
 
@@ -319,6 +493,26 @@ fn member_role_ns_key(origin: &str, account_id: u64) -> String {
     format!("member_role:{}/{}", origin, account_id)
 }
 
+fn session_key(prefix: &str, token: &str) -> String { format!("{}{}", prefix, hash_key(token)) }
+
+fn invalid_token_key(prefix: &str, token: &str) -> String {
+    format!("{}invalid_token:{}", prefix, hash_key(token))
+}
+
+fn rate_limit_key(prefix: &str, bucket: &str, part: &str) -> String {
+    format!("{}rate_limit:{}:{}", prefix, bucket, part)
+}
+
+// Tokens accrued since `refilled_at_ms` by a bucket refilling at `per_minute`
+// tokens a minute, and the refill time to record once they are added. Time that
+// has not yet earned a whole token carries over to the next refill.
+fn bucket_refill(refilled_at_ms: u64, now_ms: u64, per_minute: u32) -> (u64, u64) {
+    let per_minute = u64::from(per_minute);
+    let ms_per_minute = u64::from(RATE_LIMIT_REFILL_SECS) * 1000;
+    let tokens = now_ms.saturating_sub(refilled_at_ms) * per_minute / ms_per_minute;
+    (tokens, refilled_at_ms + tokens * ms_per_minute / per_minute)
+}
+
 fn hash_key(key: &str) -> String {
     let mut hasher = Sha512::new();
     hasher.update(key);
@@ -341,4 +535,50 @@ mod test {
         let expected = "33a8f10726b1ada86d9f60e4abbb1cb8726798a2303395cecace82225236cfc3d5a82815d1017a1dd6f8d34e8b77a51c30d972ba2031e1207679fb2a4db925ea".to_string();
         assert_eq!(hash_key(token), expected)
     }
+
+    #[test]
+    fn invalid_token_key_is_namespaced_hash() {
+        assert_eq!(invalid_token_key("", ""),
+                   format!("invalid_token:{}", hash_key("")));
+        assert_ne!(invalid_token_key("", "foo"), hash_key("foo"));
+        assert_eq!(invalid_token_key("bldr-a:", "foo"),
+                   format!("bldr-a:invalid_token:{}", hash_key("foo")));
+    }
+
+    #[test]
+    fn rate_limit_key_is_prefixed() {
+        assert_eq!(rate_limit_key("", "ip:10.0.0.1", "used"),
+                   "rate_limit:ip:10.0.0.1:used");
+        assert_eq!(rate_limit_key("bldr-a:", "ip:10.0.0.1", "used"),
+                   "bldr-a:rate_limit:ip:10.0.0.1:used");
+    }
+
+    #[test]
+    fn bucket_refill_adds_whole_tokens() {
+        assert_eq!(bucket_refill(0, 0, 60), (0, 0));
+        assert_eq!(bucket_refill(0, 999, 60), (0, 0));
+        assert_eq!(bucket_refill(0, 1_000, 60), (1, 1_000));
+        assert_eq!(bucket_refill(0, 60_000, 60), (60, 60_000));
+    }
+
+    #[test]
+    fn bucket_refill_carries_partial_tokens_over() {
+        // At 30 tokens a minute a token takes two seconds
+        assert_eq!(bucket_refill(0, 3_000, 30), (1, 2_000));
+        assert_eq!(bucket_refill(2_000, 4_000, 30), (1, 4_000));
+        // A clock that went backwards adds nothing
+        assert_eq!(bucket_refill(5_000, 4_000, 30), (0, 5_000));
+    }
+
+    #[test]
+    fn session_key_with_empty_prefix_is_unchanged() {
+        assert_eq!(session_key("", "foo"), hash_key("foo"));
+    }
+
+    #[test]
+    fn session_key_is_prefixed() {
+        assert_eq!(session_key("bldr-a:", "foo"),
+                   format!("bldr-a:{}", hash_key("foo")));
+        assert_ne!(session_key("bldr-a:", "foo"), session_key("bldr-b:", "foo"));
+    }
 }
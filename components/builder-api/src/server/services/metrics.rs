@@ -15,14 +15,18 @@
 //! Centralized definition of all Builder API metrics that we
 //! wish to track.
 
-use crate::bldr_core::metrics;
+use crate::{bldr_core::metrics,
+            protocol::originsrv::OAuthProvider};
 use std::borrow::Cow;
 
 pub enum Counter {
+    AuthFailure,
     GetPackage,
     GetChannelPackage,
     GitHubEvent,
     RouteMessage,
+    RouteMessageRetry,
+    RouteMessageTimeout,
     SearchPackages,
     UploadRequests,
     SingleUploadRequests,
@@ -38,6 +42,10 @@ pub enum Counter {
     MemcacheChannelPackageHit,
     MemcacheChannelPackageMiss,
     MemcacheChannelPackage404,
+    SessionCacheHit,
+    SessionCacheMiss,
+    SessionCreate(OAuthProvider),
+    SessionCreateFailure,
 }
 
 impl metrics::CounterMetric for Counter {}
@@ -45,10 +53,13 @@ impl metrics::CounterMetric for Counter {}
 impl metrics::Metric for Counter {
     fn id(&self) -> Cow<'static, str> {
         match *self {
+            Counter::AuthFailure => "auth.failure".into(),
             Counter::GetPackage => "get-package".into(),
             Counter::GetChannelPackage => "get-channel-package".into(),
             Counter::GitHubEvent => "github.event".into(),
             Counter::RouteMessage => "route-message".into(),
+            Counter::RouteMessageRetry => "route-message.retry".into(),
+            Counter::RouteMessageTimeout => "route-message.timeout".into(),
             Counter::SearchPackages => "search-packages".into(),
             Counter::UploadRequests => "upload-packages".into(),
             Counter::SingleUploadRequests => "upload-single".into(),
@@ -64,6 +75,12 @@ impl metrics::Metric for Counter {
             Counter::MemcacheChannelPackageHit => "memcache-channel-package.hit".into(),
             Counter::MemcacheChannelPackageMiss => "memcache-channel-package.miss".into(),
             Counter::MemcacheChannelPackage404 => "memcache-channel-package.404".into(),
+            Counter::SessionCacheHit => "session-cache.hit".into(),
+            Counter::SessionCacheMiss => "session-cache.miss".into(),
+            Counter::SessionCreate(ref p) => {
+                format!("session-create.{:?}", p).to_lowercase().into()
+            }
+            Counter::SessionCreateFailure => "session-create.failure".into(),
         }
     }
 }
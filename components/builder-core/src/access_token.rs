@@ -28,6 +28,13 @@ const BUILDER_TOKEN_LIFETIME_HOURS: i64 = 2;
 /// * SHOULD be URL-safe (just in case)
 const ACCESS_TOKEN_PREFIX: &str = "_";
 
+/// Cheap check for whether a string has the shape of an access token, i.e.
+/// that it starts with the access token prefix. This does not parse, decrypt,
+/// or otherwise validate the token.
+pub fn is_access_token(token: &str) -> bool {
+    token.starts_with(ACCESS_TOKEN_PREFIX) && token.len() > ACCESS_TOKEN_PREFIX.len()
+}
+
 /// Encapsulates the string encoding of the encrypted OriginSrv::AccessToken
 /// type, as well as logic for creating, serializing, and validating access
 /// tokens for the API.
@@ -390,6 +397,14 @@ mod tests {
             assert!(parsed.is_ok(), "Should be able to parse a user token");
         }
 
+        #[test]
+        fn is_access_token_checks_prefix() {
+            assert!(is_access_token(SAMPLE_TOKEN));
+            assert!(!is_access_token(SAMPLE_TOKEN.trim_start_matches('_')));
+            assert!(!is_access_token("_"));
+            assert!(!is_access_token(""));
+        }
+
         #[test]
         fn token_must_start_with_appropriate_prefix_to_parse() {
             let truncated = SAMPLE_TOKEN.trim_start_matches('_');
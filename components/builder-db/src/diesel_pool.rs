@@ -56,4 +56,11 @@ impl DbPool {
             Err(e) => Err(e.into()),
         }
     }
+
+    pub fn get_conn_timeout(&self, timeout: Duration) -> Result<PgPooledConnection> {
+        match self.0.get_timeout(timeout) {
+            Ok(conn) => Ok(conn),
+            Err(e) => Err(e.into()),
+        }
+    }
 }
@@ -0,0 +1,2 @@
+-- A key revision may only be uploaded once per origin
+ALTER TABLE origin_public_encryption_keys ADD UNIQUE(origin, revision);
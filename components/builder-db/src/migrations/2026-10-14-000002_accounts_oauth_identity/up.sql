@@ -0,0 +1,5 @@
+-- Remember which external identity an account signed in with
+ALTER TABLE accounts ADD COLUMN IF NOT EXISTS oauth_provider TEXT;
+ALTER TABLE accounts ADD COLUMN IF NOT EXISTS oauth_extern_id TEXT;
+
+CREATE INDEX IF NOT EXISTS accounts_oauth_identity ON accounts(oauth_provider, oauth_extern_id);
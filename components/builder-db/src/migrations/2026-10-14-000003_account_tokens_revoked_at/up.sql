@@ -0,0 +1,2 @@
+-- Revoked tokens are kept so that their use can be reported as a revocation
+ALTER TABLE account_tokens ADD COLUMN IF NOT EXISTS revoked_at TIMESTAMPTZ;
@@ -0,0 +1,3 @@
+-- Revoked access tokens are kept alongside the account's current token
+ALTER TABLE account_tokens DROP CONSTRAINT IF EXISTS account_tokens_account_id_key;
+CREATE INDEX IF NOT EXISTS account_tokens_account_id ON account_tokens(account_id);
@@ -1,9 +1,12 @@
 use super::db_id_format;
-use chrono::NaiveDateTime;
+use chrono::{NaiveDateTime,
+             Utc};
 use diesel::{self,
              pg::PgConnection,
-             result::QueryResult,
+             result::{Error as DieselError,
+                      QueryResult},
              ExpressionMethods,
+             OptionalExtension,
              QueryDsl,
              RunQueryDsl};
 
@@ -15,11 +18,16 @@ use crate::{bldr_core::metrics::CounterMetric,
 #[derive(Debug, Identifiable, Serialize, Queryable)]
 pub struct Account {
     #[serde(with = "db_id_format")]
-    pub id:         i64,
-    pub email:      String,
-    pub name:       String,
-    pub created_at: Option<NaiveDateTime>,
-    pub updated_at: Option<NaiveDateTime>,
+    pub id:              i64,
+    pub email:           String,
+    pub name:            String,
+    pub created_at:      Option<NaiveDateTime>,
+    pub updated_at:      Option<NaiveDateTime>,
+    // Kept out of API responses such as GET /profile
+    #[serde(skip_serializing)]
+    pub oauth_provider:  Option<String>,
+    #[serde(skip_serializing)]
+    pub oauth_extern_id: Option<String>,
 }
 
 #[derive(Identifiable, Debug, Serialize, Queryable)]
@@ -31,13 +39,16 @@ pub struct AccountToken {
     pub account_id: i64,
     pub token:      String,
     pub created_at: Option<NaiveDateTime>,
+    pub revoked_at: Option<NaiveDateTime>,
 }
 
 #[derive(Insertable)]
 #[table_name = "accounts"]
 pub struct NewAccount<'a> {
-    pub email: &'a str,
-    pub name:  &'a str,
+    pub email:           &'a str,
+    pub name:            &'a str,
+    pub oauth_provider:  Option<&'a str>,
+    pub oauth_extern_id: Option<&'a str>,
 }
 
 impl Account {
@@ -58,6 +69,11 @@ impl Account {
                                             .get_result(conn)
     }
 
+    /// Returns the account with this name, creating it if needed. This is safe
+    /// under concurrent first sign ins: the insert does nothing on a name
+    /// conflict, so the losing caller reads the account the winner created
+    /// rather than seeing a unique violation. Database errors other than the
+    /// conflict are returned as is.
     pub fn find_or_create(account: &NewAccount, conn: &PgConnection) -> QueryResult<Account> {
         Counter::DBCall.increment();
         match diesel::insert_into(accounts::table).values(account)
@@ -66,17 +82,44 @@ impl Account {
                                                   .get_result(conn)
         {
             Ok(account) => Ok(account),
-            Err(_) => {
-                accounts::table.filter(accounts::name.eq(account.name))
-                               .get_result(conn)
+            Err(DieselError::NotFound) => {
+                let existing: Account = accounts::table.filter(accounts::name.eq(account.name))
+                                                       .get_result(conn)?;
+                if existing.oauth_extern_id.is_some() || account.oauth_extern_id.is_none() {
+                    return Ok(existing);
+                }
+                // Accounts created before external identities were stored pick
+                // theirs up on next sign in. An identity is never overwritten.
+                Counter::DBCall.increment();
+                let updated =
+                    diesel::update(accounts::table.find(existing.id)
+                                                  .filter(accounts::oauth_extern_id.is_null()))
+                        .set((accounts::oauth_provider.eq(account.oauth_provider),
+                              accounts::oauth_extern_id.eq(account.oauth_extern_id)))
+                        .get_result(conn)
+                        .optional()?;
+                Ok(updated.unwrap_or(existing))
             }
+            Err(e) => Err(e),
         }
     }
 
-    pub fn update(id: u64, email: &str, conn: &PgConnection) -> QueryResult<usize> {
+    pub fn find_by_extern_id(provider: &str,
+                             extern_id: &str,
+                             conn: &PgConnection)
+                             -> QueryResult<Account> {
+        Counter::DBCall.increment();
+        accounts::table.filter(accounts::oauth_provider.eq(provider.to_lowercase()))
+                       .filter(accounts::oauth_extern_id.eq(extern_id))
+                       .get_result(conn)
+    }
+
+    /// Changes an account's email, returning the updated account or `NotFound`
+    /// if there is no account with this id
+    pub fn update_email(id: u64, email: &str, conn: &PgConnection) -> QueryResult<Account> {
         Counter::DBCall.increment();
         diesel::update(accounts::table.find(id as i64)).set(accounts::email.eq(email))
-                                                       .execute(conn)
+                                                       .get_result(conn)
     }
 }
 
@@ -88,21 +131,31 @@ pub struct NewAccountToken<'a> {
 }
 
 impl AccountToken {
+    /// Lists an account's tokens, including revoked ones
     pub fn list(account_id: u64, conn: &PgConnection) -> QueryResult<Vec<AccountToken>> {
         Counter::DBCall.increment();
         account_tokens::table.filter(account_tokens::account_id.eq(account_id as i64))
                              .get_results(conn)
     }
 
+    /// Adds a token to an account. Existing tokens are left as they are.
     pub fn create(req: &NewAccountToken, conn: &PgConnection) -> QueryResult<AccountToken> {
         Counter::DBCall.increment();
         diesel::insert_into(account_tokens::table).values(req)
-                                                  .on_conflict(account_tokens::account_id)
-                                                  .do_update()
-                                                  .set(account_tokens::token.eq(req.token))
                                                   .get_result(conn)
     }
 
+    /// Marks a token as revoked. The row is kept so that later attempts to use
+    /// the token can be told apart from tokens that never existed.
+    pub fn revoke(id: u64, conn: &PgConnection) -> QueryResult<usize> {
+        Counter::DBCall.increment();
+        diesel::update(account_tokens::table.find(id as i64))
+            .set(account_tokens::revoked_at.eq(Utc::now().naive_utc()))
+            .execute(conn)
+    }
+
+    pub fn is_revoked(&self) -> bool { self.revoked_at.is_some() }
+
     pub fn delete(id: u64, conn: &PgConnection) -> QueryResult<usize> {
         Counter::DBCall.increment();
         diesel::delete(account_tokens::table.find(id as i64)).execute(conn)
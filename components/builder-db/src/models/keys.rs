@@ -1,14 +1,145 @@
 use super::db_id_format;
 use crate::{bldr_core::metrics::CounterMetric,
             metrics::Counter,
-            schema::key::*};
-use chrono::NaiveDateTime;
+            protocol::originsrv,
+            schema::{account::accounts,
+                     key::*}};
+use chrono::{Duration,
+             NaiveDateTime,
+             Utc};
 use diesel::{self,
+             dsl::{exists,
+                   max},
              pg::PgConnection,
-             result::QueryResult,
+             result::{DatabaseErrorKind,
+                      Error as DieselError,
+                      QueryResult},
+             Connection,
              ExpressionMethods,
+             OptionalExtension,
              QueryDsl,
              RunQueryDsl};
+use std::{error,
+          fmt,
+          result};
+
+// Key revisions are UTC timestamps in this format
+const REVISION_FORMAT: &str = "%Y%m%d%H%M%S";
+
+/// Default for the largest encryption key body, in bytes, that will be stored.
+/// Keys are a few hundred bytes, so anything near this is not a key.
+pub const DEFAULT_MAX_KEY_BODY_SIZE: usize = 4 * 1024;
+
+// Rows fetched per query when iterating over an origin's keys
+const FOR_EACH_BATCH_SIZE: i64 = 500;
+
+#[derive(Debug)]
+pub enum KeyError {
+    /// A key with this origin and revision already exists
+    DuplicateRevision(String),
+    /// The account a key was to be assigned to does not exist
+    OwnerNotFound(u64),
+    /// The origin a key was to be created in does not exist
+    OriginNotFound(String),
+    /// The key body, of the given size, is larger than the given limit
+    BodyTooLarge(usize, usize),
+    /// A listing was asked for a negative offset or a limit below 1
+    InvalidRange(i64, i64),
+    Database(DieselError),
+}
+
+pub type KeyResult<T> = result::Result<T, KeyError>;
+
+impl fmt::Display for KeyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            KeyError::DuplicateRevision(ref r) => write!(f, "Key revision {} already exists", r),
+            KeyError::OwnerNotFound(id) => write!(f, "Account {} does not exist", id),
+            KeyError::OriginNotFound(ref o) => write!(f, "Origin {} does not exist", o),
+            KeyError::BodyTooLarge(size, max) => {
+                write!(f,
+                       "Key body of {} bytes exceeds the {} byte limit",
+                       size, max)
+            }
+            KeyError::InvalidRange(offset, limit) => {
+                write!(f,
+                       "Invalid range offset {} limit {}, the offset must not be negative and the \
+                        limit must be at least 1",
+                       offset, limit)
+            }
+            KeyError::Database(ref e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl error::Error for KeyError {}
+
+impl From<DieselError> for KeyError {
+    fn from(err: DieselError) -> KeyError { KeyError::Database(err) }
+}
+
+fn next_revision_after(latest: Option<&str>, now: NaiveDateTime) -> String {
+    let latest = latest.and_then(|r| NaiveDateTime::parse_from_str(r, REVISION_FORMAT).ok());
+    match latest {
+        Some(latest) if latest >= now => {
+            (latest + Duration::seconds(1)).format(REVISION_FORMAT)
+                                           .to_string()
+        }
+        _ => now.format(REVISION_FORMAT).to_string(),
+    }
+}
+
+fn check_body_size(body: &str, max_body_size: usize) -> KeyResult<()> {
+    if body.len() > max_body_size {
+        return Err(KeyError::BodyTooLarge(body.len(), max_body_size));
+    }
+    Ok(())
+}
+
+fn check_range(offset: i64, limit: i64) -> KeyResult<()> {
+    if offset < 0 || limit < 1 {
+        return Err(KeyError::InvalidRange(offset, limit));
+    }
+    Ok(())
+}
+
+// Freshly created keys should always report when they were created, even if the
+// column default did not fill it in
+fn default_created_at(created_at: &mut Option<NaiveDateTime>) {
+    if created_at.is_none() {
+        *created_at = Some(Utc::now().naive_utc());
+    }
+}
+
+// Translate constraint violations on insert into typed errors: a unique
+// violation is a duplicate revision, and the only foreign key is the origin
+fn create_error(err: DieselError, origin: &str, revision: &str) -> KeyError {
+    match err {
+        DieselError::DatabaseError(DatabaseErrorKind::UniqueViolation, _) => {
+            KeyError::DuplicateRevision(revision.to_string())
+        }
+        DieselError::DatabaseError(DatabaseErrorKind::ForeignKeyViolation, _) => {
+            KeyError::OriginNotFound(origin.to_string())
+        }
+        e => KeyError::Database(e),
+    }
+}
+
+// A batch insert fails as a whole. Postgres names the conflicting key in the
+// error details, so report that row where possible, and otherwise the first
+fn create_batch_error(err: DieselError, reqs: &[NewOriginPublicEncryptionKey]) -> KeyError {
+    let culprit = match err {
+        DieselError::DatabaseError(_, ref info) => {
+            info.details()
+                .and_then(|details| reqs.iter().find(|req| details.contains(req.revision)))
+        }
+        _ => None,
+    };
+    match culprit.or_else(|| reqs.first()) {
+        Some(req) => create_error(err, req.origin, req.revision),
+        None => KeyError::Database(err),
+    }
+}
 
 #[derive(Debug, Serialize, Deserialize, QueryableByName, Queryable)]
 #[table_name = "origin_public_encryption_keys"]
@@ -26,6 +157,22 @@ pub struct OriginPublicEncryptionKey {
     pub origin:     String,
 }
 
+/// An origin public encryption key without its body, for listings that only
+/// need to identify keys
+#[derive(Debug, Serialize, Deserialize, Queryable)]
+pub struct OriginPublicEncryptionKeyMeta {
+    #[serde(with = "db_id_format")]
+    pub id:         i64,
+    #[serde(with = "db_id_format")]
+    pub owner_id:   i64,
+    pub name:       String,
+    pub revision:   String,
+    pub full_name:  String,
+    pub created_at: Option<NaiveDateTime>,
+    pub updated_at: Option<NaiveDateTime>,
+    pub origin:     String,
+}
+
 #[derive(Debug, Serialize, Deserialize, QueryableByName, Queryable)]
 #[table_name = "origin_private_encryption_keys"]
 pub struct OriginPrivateEncryptionKey {
@@ -75,6 +222,14 @@ pub struct OriginPublicSigningKey {
     pub origin:     String,
 }
 
+/// The latest public and private encryption keys for an origin, read together
+/// so that they are consistent with each other. Either side may be missing.
+#[derive(Debug, Serialize)]
+pub struct OriginEncryptionKeyPair {
+    pub public:  Option<OriginPublicEncryptionKey>,
+    pub private: Option<OriginPrivateEncryptionKey>,
+}
+
 #[derive(Insertable)]
 #[table_name = "origin_public_encryption_keys"]
 pub struct NewOriginPublicEncryptionKey<'a> {
@@ -134,12 +289,63 @@ impl OriginPublicEncryptionKey {
             .get_result(conn)
     }
 
+    /// Gets the given revision of a key, or the latest one if no revision is given
+    pub fn get_or_latest(origin: &str,
+                         revision: Option<&str>,
+                         conn: &PgConnection)
+                         -> QueryResult<OriginPublicEncryptionKey> {
+        match revision {
+            Some(revision) => Self::get(origin, revision, conn),
+            None => Self::latest(origin, conn),
+        }
+    }
+
+    /// Looks a key up by its full name, e.g. `myorigin-20180101000000`
+    pub fn get_by_full_name(full_name: &str,
+                            conn: &PgConnection)
+                            -> QueryResult<OriginPublicEncryptionKey> {
+        Counter::DBCall.increment();
+        origin_public_encryption_keys::table
+            .filter(origin_public_encryption_keys::full_name.eq(full_name))
+            .get_result(conn)
+    }
+
+    /// Creates a key, rejecting bodies larger than `max_body_size` bytes. The
+    /// returned key always has `created_at` set; if the database did not
+    /// populate it, the current time is used.
     pub fn create(req: &NewOriginPublicEncryptionKey,
+                  max_body_size: usize,
                   conn: &PgConnection)
-                  -> QueryResult<OriginPublicEncryptionKey> {
+                  -> KeyResult<OriginPublicEncryptionKey> {
+        check_body_size(req.body, max_body_size)?;
         Counter::DBCall.increment();
-        diesel::insert_into(origin_public_encryption_keys::table).values(req)
-                                                                 .get_result(conn)
+        let res = diesel::insert_into(origin_public_encryption_keys::table).values(req)
+                                                                           .get_result(conn);
+        let mut key: OriginPublicEncryptionKey =
+            res.map_err(|e| create_error(e, req.origin, req.revision))?;
+        default_created_at(&mut key.created_at);
+        Ok(key)
+    }
+
+    /// Creates several keys at once. This is a single multi-row INSERT, so if
+    /// any row fails (e.g. a duplicate revision) none of the keys are created.
+    /// As with `create`, the returned keys always have `created_at` set.
+    pub fn create_batch(reqs: &[NewOriginPublicEncryptionKey],
+                        max_body_size: usize,
+                        conn: &PgConnection)
+                        -> KeyResult<Vec<OriginPublicEncryptionKey>> {
+        for req in reqs {
+            check_body_size(req.body, max_body_size)?;
+        }
+        Counter::DBCall.increment();
+        let res = diesel::insert_into(origin_public_encryption_keys::table).values(reqs)
+                                                                           .get_results(conn);
+        let mut keys: Vec<OriginPublicEncryptionKey> =
+            res.map_err(|e| create_batch_error(e, reqs))?;
+        for key in keys.iter_mut() {
+            default_created_at(&mut key.created_at);
+        }
+        Ok(keys)
     }
 
     pub fn latest(origin: &str, conn: &PgConnection) -> QueryResult<OriginPublicEncryptionKey> {
@@ -158,6 +364,182 @@ impl OriginPublicEncryptionKey {
             .order(origin_public_encryption_keys::revision.desc())
             .get_results(conn)
     }
+
+    /// Lists the keys of several origins in a single query, grouped by origin
+    /// and newest first within each origin
+    pub fn list_for_origins(origins: &[&str],
+                            conn: &PgConnection)
+                            -> QueryResult<Vec<OriginPublicEncryptionKey>> {
+        if origins.is_empty() {
+            return Ok(Vec::new());
+        }
+        Counter::DBCall.increment();
+        origin_public_encryption_keys::table
+            .filter(origin_public_encryption_keys::origin.eq_any(origins))
+            .order((origin_public_encryption_keys::origin.asc(),
+                    origin_public_encryption_keys::revision.desc()))
+            .get_results(conn)
+    }
+
+    /// Calls `f` with each of an origin's keys in id order, stopping at the first
+    /// error. Diesel has no cursor support, so rows are fetched in batches of
+    /// `FOR_EACH_BATCH_SIZE` and at most one batch is held in memory at a time.
+    pub fn for_each<F, E>(origin: &str, conn: &PgConnection, mut f: F) -> result::Result<(), E>
+        where F: FnMut(OriginPublicEncryptionKey) -> result::Result<(), E>,
+              E: From<DieselError>
+    {
+        let mut last_id = 0;
+        loop {
+            Counter::DBCall.increment();
+            let batch: Vec<OriginPublicEncryptionKey> =
+                origin_public_encryption_keys::table
+                    .filter(origin_public_encryption_keys::origin.eq(origin))
+                    .filter(origin_public_encryption_keys::id.gt(last_id))
+                    .order(origin_public_encryption_keys::id.asc())
+                    .limit(FOR_EACH_BATCH_SIZE)
+                    .get_results(conn)?;
+            let done = (batch.len() as i64) < FOR_EACH_BATCH_SIZE;
+            for key in batch {
+                last_id = key.id;
+                f(key)?;
+            }
+            if done {
+                return Ok(());
+            }
+        }
+    }
+
+    // Keys with no created_at are never within a range
+    pub fn list_created_between(origin: &str,
+                                start: NaiveDateTime,
+                                end: NaiveDateTime,
+                                conn: &PgConnection)
+                                -> QueryResult<Vec<OriginPublicEncryptionKey>> {
+        Counter::DBCall.increment();
+        origin_public_encryption_keys::table
+            .filter(origin_public_encryption_keys::origin.eq(origin))
+            .filter(origin_public_encryption_keys::created_at.is_not_null())
+            .filter(origin_public_encryption_keys::created_at.between(start, end))
+            .order(origin_public_encryption_keys::created_at.asc())
+            .get_results(conn)
+    }
+
+    pub fn list_meta(origin: &str,
+                     conn: &PgConnection)
+                     -> QueryResult<Vec<OriginPublicEncryptionKeyMeta>> {
+        Counter::DBCall.increment();
+        origin_public_encryption_keys::table
+            .select((origin_public_encryption_keys::id,
+                     origin_public_encryption_keys::owner_id,
+                     origin_public_encryption_keys::name,
+                     origin_public_encryption_keys::revision,
+                     origin_public_encryption_keys::full_name,
+                     origin_public_encryption_keys::created_at,
+                     origin_public_encryption_keys::updated_at,
+                     origin_public_encryption_keys::origin))
+            .filter(origin_public_encryption_keys::origin.eq(origin))
+            .order(origin_public_encryption_keys::revision.desc())
+            .get_results(conn)
+    }
+
+    /// Returns up to `limit` of an origin's keys, oldest first, skipping the
+    /// first `offset`, along with the total number of keys. A negative
+    /// `offset` or a `limit` below 1 is rejected with `InvalidRange` without
+    /// querying the database.
+    pub fn list_paginated(origin: &str,
+                          offset: i64,
+                          limit: i64,
+                          conn: &PgConnection)
+                          -> KeyResult<(Vec<OriginPublicEncryptionKey>, i64)> {
+        check_range(offset, limit)?;
+        Counter::DBCall.increment();
+        let keys = origin_public_encryption_keys::table
+            .filter(origin_public_encryption_keys::origin.eq(origin))
+            .order((origin_public_encryption_keys::created_at.asc(),
+                    origin_public_encryption_keys::id.asc()))
+            .limit(limit)
+            .offset(offset)
+            .get_results(conn)?;
+        let total = Self::count(origin, conn)?;
+        Ok((keys, total))
+    }
+
+    pub fn count(origin: &str, conn: &PgConnection) -> QueryResult<i64> {
+        Counter::DBCall.increment();
+        origin_public_encryption_keys::table
+            .filter(origin_public_encryption_keys::origin.eq(origin))
+            .count()
+            .get_result(conn)
+    }
+
+    /// Suggests a revision for a new key: the current UTC time, or one second
+    /// past the latest existing revision if that is not already in the past.
+    pub fn next_revision(origin: &str, conn: &PgConnection) -> QueryResult<String> {
+        Counter::DBCall.increment();
+        let latest: Option<String> =
+            origin_public_encryption_keys::table
+                .filter(origin_public_encryption_keys::origin.eq(origin))
+                .select(max(origin_public_encryption_keys::revision))
+                .get_result(conn)?;
+        Ok(next_revision_after(latest.as_ref().map(String::as_str), Utc::now().naive_utc()))
+    }
+
+    /// Lists the keys uploaded by an account across all origins, newest first
+    pub fn list_by_owner(owner_id: u64,
+                         conn: &PgConnection)
+                         -> QueryResult<Vec<OriginPublicEncryptionKey>> {
+        Counter::DBCall.increment();
+        origin_public_encryption_keys::table
+            .filter(origin_public_encryption_keys::owner_id.eq(owner_id as i64))
+            .order(origin_public_encryption_keys::created_at.desc())
+            .get_results(conn)
+    }
+
+    /// Hands every public encryption key of an origin over to a new owner,
+    /// returning the number of keys updated. Owner ids are not constrained by the
+    /// schema, so the account is checked in the same transaction.
+    pub fn reassign_owner(origin: &str,
+                          new_owner_id: u64,
+                          conn: &PgConnection)
+                          -> KeyResult<usize> {
+        Counter::DBCall.increment();
+        conn.transaction::<_, KeyError, _>(|| {
+                let owner_exists: bool =
+                    diesel::select(exists(accounts::table.find(new_owner_id as i64)))
+                        .get_result(conn)?;
+                if !owner_exists {
+                    return Err(KeyError::OwnerNotFound(new_owner_id));
+                }
+
+                diesel::update(
+                    origin_public_encryption_keys::table
+                        .filter(origin_public_encryption_keys::origin.eq(origin)),
+                )
+                .set(origin_public_encryption_keys::owner_id.eq(new_owner_id as i64))
+                .execute(conn)
+                .map_err(KeyError::Database)
+            })
+    }
+
+    pub fn exists(origin: &str, revision: &str, conn: &PgConnection) -> QueryResult<bool> {
+        Counter::DBCall.increment();
+        diesel::select(exists(
+            origin_public_encryption_keys::table
+                .filter(origin_public_encryption_keys::origin.eq(origin))
+                .filter(origin_public_encryption_keys::revision.eq(revision)),
+        ))
+        .get_result(conn)
+    }
+
+    pub fn delete(origin: &str, revision: &str, conn: &PgConnection) -> QueryResult<usize> {
+        Counter::DBCall.increment();
+        diesel::delete(
+            origin_public_encryption_keys::table
+                .filter(origin_public_encryption_keys::origin.eq(origin))
+                .filter(origin_public_encryption_keys::revision.eq(revision)),
+        )
+        .execute(conn)
+    }
 }
 
 impl OriginPrivateEncryptionKey {
@@ -171,12 +553,108 @@ impl OriginPrivateEncryptionKey {
             .get_result(conn)
     }
 
+    /// Creates a key, rejecting bodies larger than `max_body_size` bytes. The
+    /// returned key always has `created_at` set; if the database did not
+    /// populate it, the current time is used.
     pub fn create(req: &NewOriginPrivateEncryptionKey,
+                  max_body_size: usize,
                   conn: &PgConnection)
-                  -> QueryResult<OriginPrivateEncryptionKey> {
+                  -> KeyResult<OriginPrivateEncryptionKey> {
+        check_body_size(req.body, max_body_size)?;
         Counter::DBCall.increment();
-        diesel::insert_into(origin_private_encryption_keys::table).values(req)
-                                                                  .get_result(conn)
+        let res = diesel::insert_into(origin_private_encryption_keys::table).values(req)
+                                                                            .get_result(conn);
+        let mut key: OriginPrivateEncryptionKey =
+            res.map_err(|e| create_error(e, req.origin, req.revision))?;
+        default_created_at(&mut key.created_at);
+        Ok(key)
+    }
+
+    pub fn latest(origin: &str, conn: &PgConnection) -> QueryResult<OriginPrivateEncryptionKey> {
+        Counter::DBCall.increment();
+        origin_private_encryption_keys::table
+            .filter(origin_private_encryption_keys::origin.eq(origin))
+            .limit(1)
+            .order(origin_private_encryption_keys::revision.desc())
+            .get_result(conn)
+    }
+
+    pub fn list(origin: &str, conn: &PgConnection) -> QueryResult<Vec<OriginPrivateEncryptionKey>> {
+        Counter::DBCall.increment();
+        origin_private_encryption_keys::table
+            .filter(origin_private_encryption_keys::origin.eq(origin))
+            .order(origin_private_encryption_keys::revision.desc())
+            .get_results(conn)
+    }
+
+    pub fn list_created_between(origin: &str,
+                                start: NaiveDateTime,
+                                end: NaiveDateTime,
+                                conn: &PgConnection)
+                                -> QueryResult<Vec<OriginPrivateEncryptionKey>> {
+        Counter::DBCall.increment();
+        origin_private_encryption_keys::table
+            .filter(origin_private_encryption_keys::origin.eq(origin))
+            .filter(origin_private_encryption_keys::created_at.is_not_null())
+            .filter(origin_private_encryption_keys::created_at.between(start, end))
+            .order(origin_private_encryption_keys::created_at.asc())
+            .get_results(conn)
+    }
+
+    pub fn count(origin: &str, conn: &PgConnection) -> QueryResult<i64> {
+        Counter::DBCall.increment();
+        origin_private_encryption_keys::table
+            .filter(origin_private_encryption_keys::origin.eq(origin))
+            .count()
+            .get_result(conn)
+    }
+
+    /// Lists the private keys uploaded by an account across all origins, newest
+    /// first. Callers are responsible for authorizing access to the key bodies.
+    pub fn list_by_owner(owner_id: u64,
+                         conn: &PgConnection)
+                         -> QueryResult<Vec<OriginPrivateEncryptionKey>> {
+        Counter::DBCall.increment();
+        origin_private_encryption_keys::table
+            .filter(origin_private_encryption_keys::owner_id.eq(owner_id as i64))
+            .order(origin_private_encryption_keys::created_at.desc())
+            .get_results(conn)
+    }
+
+    pub fn exists(origin: &str, revision: &str, conn: &PgConnection) -> QueryResult<bool> {
+        Counter::DBCall.increment();
+        diesel::select(exists(
+            origin_private_encryption_keys::table
+                .filter(origin_private_encryption_keys::origin.eq(origin))
+                .filter(origin_private_encryption_keys::revision.eq(revision)),
+        ))
+        .get_result(conn)
+    }
+
+    // Private keys are only ever fetched as the latest for an origin, so they are
+    // purged for the whole origin at once
+    pub fn delete(origin: &str, conn: &PgConnection) -> QueryResult<usize> {
+        Counter::DBCall.increment();
+        diesel::delete(
+            origin_private_encryption_keys::table
+                .filter(origin_private_encryption_keys::origin.eq(origin)),
+        )
+        .execute(conn)
+    }
+}
+
+impl OriginEncryptionKeyPair {
+    pub fn latest(origin: &str, conn: &PgConnection) -> QueryResult<OriginEncryptionKeyPair> {
+        // Repeatable read so both keys come from the same snapshot, even if a key
+        // rotation commits between the two queries
+        conn.build_transaction()
+            .repeatable_read()
+            .read_only()
+            .run(|| {
+                let public = OriginPublicEncryptionKey::latest(origin, conn).optional()?;
+                let private = OriginPrivateEncryptionKey::latest(origin, conn).optional()?;
+                Ok(OriginEncryptionKeyPair { public, private })
+            })
     }
 }
 
@@ -218,21 +696,29 @@ impl OriginPublicSigningKey {
 }
 
 impl OriginPrivateSigningKey {
-    pub fn get(origin: &str, conn: &PgConnection) -> QueryResult<OriginPrivateSigningKey> {
+    pub fn create(req: &NewOriginPrivateSigningKey,
+                  conn: &PgConnection)
+                  -> QueryResult<OriginPrivateSigningKey> {
+        Counter::DBCall.increment();
+        diesel::insert_into(origin_secret_keys::table).values(req)
+                                                      .get_result(conn)
+    }
+
+    // There is no lookup by revision, since old private signing keys can't be
+    // fetched
+    pub fn latest(origin: &str, conn: &PgConnection) -> QueryResult<OriginPrivateSigningKey> {
         Counter::DBCall.increment();
-        // This is really latest because you're not allowed to get old keys
         origin_secret_keys::table.filter(origin_secret_keys::origin.eq(origin))
                                  .limit(1)
-                                 .order(origin_secret_keys::full_name.desc())
+                                 .order(origin_secret_keys::revision.desc())
                                  .get_result(conn)
     }
 
-    pub fn create(req: &NewOriginPrivateSigningKey,
-                  conn: &PgConnection)
-                  -> QueryResult<OriginPrivateSigningKey> {
+    pub fn list(origin: &str, conn: &PgConnection) -> QueryResult<Vec<OriginPrivateSigningKey>> {
         Counter::DBCall.increment();
-        diesel::insert_into(origin_secret_keys::table).values(req)
-                                                      .get_result(conn)
+        origin_secret_keys::table.filter(origin_secret_keys::origin.eq(origin))
+                                 .order(origin_secret_keys::revision.desc())
+                                 .get_results(conn)
     }
 
     pub fn update_key(id: i64,
@@ -264,3 +750,198 @@ impl OriginPrivateSigningKey {
                                  .get_results(conn)
     }
 }
+
+impl Into<originsrv::OriginPublicEncryptionKey> for OriginPublicEncryptionKey {
+    fn into(self) -> originsrv::OriginPublicEncryptionKey {
+        let mut key = originsrv::OriginPublicEncryptionKey::new();
+        key.set_id(self.id as u64);
+        key.set_owner_id(self.owner_id as u64);
+        key.set_name(self.name);
+        key.set_revision(self.revision);
+        key.set_full_name(self.full_name);
+        key.set_body(self.body);
+        key.set_origin(self.origin);
+        key
+    }
+}
+
+impl From<originsrv::OriginPublicEncryptionKey> for OriginPublicEncryptionKey {
+    fn from(key: originsrv::OriginPublicEncryptionKey) -> OriginPublicEncryptionKey {
+        OriginPublicEncryptionKey { id:         key.get_id() as i64,
+                                    owner_id:   key.get_owner_id() as i64,
+                                    name:       key.get_name().to_string(),
+                                    revision:   key.get_revision().to_string(),
+                                    full_name:  key.get_full_name().to_string(),
+                                    body:       key.get_body().to_string(),
+                                    created_at: None,
+                                    updated_at: None,
+                                    origin:     key.get_origin().to_string(), }
+    }
+}
+
+impl Into<originsrv::OriginPrivateEncryptionKey> for OriginPrivateEncryptionKey {
+    fn into(self) -> originsrv::OriginPrivateEncryptionKey {
+        let mut key = originsrv::OriginPrivateEncryptionKey::new();
+        key.set_id(self.id as u64);
+        key.set_owner_id(self.owner_id as u64);
+        key.set_name(self.name);
+        key.set_revision(self.revision);
+        key.set_full_name(self.full_name);
+        key.set_body(self.body);
+        key.set_origin(self.origin);
+        key
+    }
+}
+
+impl From<originsrv::OriginPrivateEncryptionKey> for OriginPrivateEncryptionKey {
+    fn from(key: originsrv::OriginPrivateEncryptionKey) -> OriginPrivateEncryptionKey {
+        OriginPrivateEncryptionKey { id:         key.get_id() as i64,
+                                     owner_id:   key.get_owner_id() as i64,
+                                     name:       key.get_name().to_string(),
+                                     revision:   key.get_revision().to_string(),
+                                     full_name:  key.get_full_name().to_string(),
+                                     body:       key.get_body().to_string(),
+                                     created_at: None,
+                                     updated_at: None,
+                                     origin:     key.get_origin().to_string(), }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(revision: &str) -> NaiveDateTime {
+        NaiveDateTime::parse_from_str(revision, REVISION_FORMAT).unwrap()
+    }
+
+    #[test]
+    fn public_encryption_key_protobuf_round_trip() {
+        let key = OriginPublicEncryptionKey { id:         1,
+                                              owner_id:   2,
+                                              name:       "neurosis".to_string(),
+                                              revision:   "20200101000000".to_string(),
+                                              full_name:  "neurosis-20200101000000".to_string(),
+                                              body:       "body".to_string(),
+                                              created_at: None,
+                                              updated_at: None,
+                                              origin:     "neurosis".to_string(), };
+        let msg: originsrv::OriginPublicEncryptionKey = key.into();
+        assert_eq!(msg.get_owner_id(), 2);
+        assert_eq!(msg.get_full_name(), "neurosis-20200101000000");
+
+        let key = OriginPublicEncryptionKey::from(msg);
+        assert_eq!(key.id, 1);
+        assert_eq!(key.revision, "20200101000000");
+        assert_eq!(key.body, "body");
+    }
+
+    #[test]
+    fn create_error_translates_constraint_violations() {
+        let violation =
+            |kind| DieselError::DatabaseError(kind, Box::new(String::from("violation")));
+
+        match create_error(violation(DatabaseErrorKind::ForeignKeyViolation),
+                           "bogus",
+                           "20200101000000")
+        {
+            KeyError::OriginNotFound(origin) => assert_eq!(origin, "bogus"),
+            e => panic!("unexpected error {:?}", e),
+        }
+        match create_error(violation(DatabaseErrorKind::UniqueViolation),
+                           "bogus",
+                           "20200101000000")
+        {
+            KeyError::DuplicateRevision(revision) => assert_eq!(revision, "20200101000000"),
+            e => panic!("unexpected error {:?}", e),
+        }
+        match create_error(DieselError::NotFound, "bogus", "20200101000000") {
+            KeyError::Database(DieselError::NotFound) => (),
+            e => panic!("unexpected error {:?}", e),
+        }
+    }
+
+    #[test]
+    fn create_batch_error_reports_a_row() {
+        let new_key = |revision| {
+            NewOriginPublicEncryptionKey { owner_id: 1,
+                                           origin: "neurosis",
+                                           name: "neurosis",
+                                           full_name: "neurosis",
+                                           revision,
+                                           body: "body" }
+        };
+        let reqs = [new_key("20200101000000"), new_key("20200102000000")];
+        let violation = DieselError::DatabaseError(DatabaseErrorKind::UniqueViolation,
+                                                   Box::new(String::from("violation")));
+
+        match create_batch_error(violation, &reqs) {
+            KeyError::DuplicateRevision(revision) => assert_eq!(revision, "20200101000000"),
+            e => panic!("unexpected error {:?}", e),
+        }
+        match create_batch_error(DieselError::NotFound, &[]) {
+            KeyError::Database(DieselError::NotFound) => (),
+            e => panic!("unexpected error {:?}", e),
+        }
+    }
+
+    #[test]
+    fn check_body_size_rejects_oversized_bodies() {
+        assert!(check_body_size(&"k".repeat(DEFAULT_MAX_KEY_BODY_SIZE),
+                                DEFAULT_MAX_KEY_BODY_SIZE).is_ok());
+        match check_body_size(&"k".repeat(DEFAULT_MAX_KEY_BODY_SIZE + 1),
+                              DEFAULT_MAX_KEY_BODY_SIZE)
+        {
+            Err(KeyError::BodyTooLarge(size, max)) => {
+                assert_eq!(size, DEFAULT_MAX_KEY_BODY_SIZE + 1);
+                assert_eq!(max, DEFAULT_MAX_KEY_BODY_SIZE);
+            }
+            res => panic!("unexpected result {:?}", res),
+        }
+        assert!(check_body_size("k", 0).is_err());
+    }
+
+    #[test]
+    fn check_range_rejects_negative_offsets_and_empty_limits() {
+        assert!(check_range(0, 1).is_ok());
+        assert!(check_range(50, 50).is_ok());
+        for &(offset, limit) in &[(-1, 50), (0, 0), (0, -1)] {
+            match check_range(offset, limit) {
+                Err(KeyError::InvalidRange(o, l)) => assert_eq!((o, l), (offset, limit)),
+                res => panic!("unexpected result {:?}", res),
+            }
+        }
+    }
+
+    #[test]
+    fn default_created_at_fills_missing_timestamp() {
+        let mut created_at = None;
+        default_created_at(&mut created_at);
+        assert!(created_at.is_some());
+
+        let existing = at("20200101000000");
+        let mut created_at = Some(existing);
+        default_created_at(&mut created_at);
+        assert_eq!(created_at, Some(existing));
+    }
+
+    #[test]
+    fn next_revision_without_keys_is_now() {
+        assert_eq!(next_revision_after(None, at("20200101000000")),
+                   "20200101000000");
+    }
+
+    #[test]
+    fn next_revision_after_older_key_is_now() {
+        assert_eq!(next_revision_after(Some("20190101000000"), at("20200101000000")),
+                   "20200101000000");
+    }
+
+    #[test]
+    fn next_revision_sorts_after_current_or_future_key() {
+        assert_eq!(next_revision_after(Some("20200101000000"), at("20200101000000")),
+                   "20200101000001");
+        assert_eq!(next_revision_after(Some("20200101235959"), at("20200101000000")),
+                   "20200102000000");
+    }
+}
@@ -5,6 +5,8 @@ table! {
         name -> Text,
         created_at -> Nullable<Timestamptz>,
         updated_at -> Nullable<Timestamptz>,
+        oauth_provider -> Nullable<Text>,
+        oauth_extern_id -> Nullable<Text>,
     }
 }
 
@@ -14,5 +16,6 @@ table! {
         account_id -> BigInt,
         token -> Text,
         created_at -> Nullable<Timestamptz>,
+        revoked_at -> Nullable<Timestamptz>,
     }
 }
@@ -0,0 +1,136 @@
+// These tests need the core/postgresql package that tests/db/start.sh runs
+#![cfg(feature = "postgres_tests")]
+
+use habitat_builder_db::{datastore_test,
+                         diesel_pool::DbPool,
+                         models::{account::{Account,
+                                            NewAccount},
+                                  keys::{KeyError,
+                                         NewOriginPublicEncryptionKey,
+                                         OriginPublicEncryptionKey,
+                                         DEFAULT_MAX_KEY_BODY_SIZE},
+                                  origin::{NewOrigin,
+                                           Origin},
+                                  package::PackageVisibility},
+                         pool::Pool};
+use std::{sync::{Arc,
+                 Barrier},
+          thread};
+
+struct TestDataStore {
+    diesel_pool: DbPool,
+}
+
+impl TestDataStore {
+    fn from_pool(_pool: Pool, diesel_pool: DbPool) -> Self { TestDataStore { diesel_pool } }
+
+    // The models only need the builder-db migrations, which datastore_test!
+    // runs itself
+    fn setup(&self) -> Result<(), ()> { Ok(()) }
+}
+
+// A fresh database holding the "neurosis" origin, owned by "bobo"
+fn test_pool() -> (DbPool, Account) {
+    let ds = datastore_test!(TestDataStore);
+    let conn = ds.diesel_pool.get_conn().unwrap();
+    let account = Account::create(&NewAccount { name:            "bobo",
+                                                email:           "bobo@example.com",
+                                                oauth_provider:  None,
+                                                oauth_extern_id: None, },
+                                  &*conn).unwrap();
+    Origin::create(&NewOrigin { name: "neurosis",
+                                owner_id: account.id,
+                                default_package_visibility: &PackageVisibility::Public, },
+                   &*conn).unwrap();
+    (ds.diesel_pool, account)
+}
+
+fn new_key<'a>(account: &Account,
+               origin: &'a str,
+               full_name: &'a str,
+               revision: &'a str)
+               -> NewOriginPublicEncryptionKey<'a> {
+    NewOriginPublicEncryptionKey { owner_id: account.id,
+                                   name: origin,
+                                   full_name,
+                                   revision,
+                                   body: "body",
+                                   origin }
+}
+
+#[test]
+fn get_or_latest_picks_the_given_or_latest_revision() {
+    let (pool, account) = test_pool();
+    let conn = pool.get_conn().unwrap();
+    for &(full_name, revision) in &[("neurosis-20200101000000", "20200101000000"),
+                                    ("neurosis-20200102000000", "20200102000000")]
+    {
+        OriginPublicEncryptionKey::create(&new_key(&account, "neurosis", full_name, revision),
+                                          DEFAULT_MAX_KEY_BODY_SIZE,
+                                          &*conn).unwrap();
+    }
+
+    let key = OriginPublicEncryptionKey::get_or_latest("neurosis", Some("20200101000000"), &*conn)
+        .unwrap();
+    assert_eq!(key.revision, "20200101000000");
+
+    let key = OriginPublicEncryptionKey::get_or_latest("neurosis", None, &*conn).unwrap();
+    assert_eq!(key.revision, "20200102000000");
+}
+
+#[test]
+fn creating_a_duplicate_revision_is_a_conflict() {
+    let (pool, account) = test_pool();
+    let conn = pool.get_conn().unwrap();
+    let req = new_key(&account,
+                      "neurosis",
+                      "neurosis-20200101000000",
+                      "20200101000000");
+    OriginPublicEncryptionKey::create(&req, DEFAULT_MAX_KEY_BODY_SIZE, &*conn).unwrap();
+
+    match OriginPublicEncryptionKey::create(&req, DEFAULT_MAX_KEY_BODY_SIZE, &*conn) {
+        Err(KeyError::DuplicateRevision(revision)) => assert_eq!(revision, "20200101000000"),
+        res => panic!("unexpected result {:?}", res),
+    }
+}
+
+#[test]
+fn creating_a_key_in_a_missing_origin_is_not_found() {
+    let (pool, account) = test_pool();
+    let conn = pool.get_conn().unwrap();
+    let req = new_key(&account, "bogus", "bogus-20200101000000", "20200101000000");
+
+    match OriginPublicEncryptionKey::create(&req, DEFAULT_MAX_KEY_BODY_SIZE, &*conn) {
+        Err(KeyError::OriginNotFound(origin)) => assert_eq!(origin, "bogus"),
+        res => panic!("unexpected result {:?}", res),
+    }
+}
+
+#[test]
+fn concurrent_find_or_create_makes_one_account() {
+    let (pool, _) = test_pool();
+    let barrier = Arc::new(Barrier::new(2));
+    let handles = (0..2).map(|_| {
+                            let pool = pool.clone();
+                            let barrier = barrier.clone();
+                            thread::spawn(move || {
+                                let conn = pool.get_conn().unwrap();
+                                let account = NewAccount { name:            "wesker",
+                                                           email:           "wesker@example.com",
+                                                           oauth_provider:  Some("github"),
+                                                           oauth_extern_id: Some("1234"), };
+                                barrier.wait();
+                                Account::find_or_create(&account, &*conn).unwrap()
+                            })
+                        })
+                        .collect::<Vec<_>>();
+    let ids = handles.into_iter()
+                     .map(|handle| handle.join().unwrap().id)
+                     .collect::<Vec<_>>();
+
+    let conn = pool.get_conn().unwrap();
+    let account = Account::get("wesker", &*conn).unwrap();
+    assert_eq!(ids, vec![account.id, account.id]);
+    assert_eq!(account.oauth_extern_id.as_ref().map(String::as_str),
+               Some("1234"));
+}
@@ -31,6 +31,8 @@ pub struct Bitbucket;
 #[derive(Deserialize)]
 struct AuthOk {
     pub access_token: String,
+    #[serde(default)]
+    pub scope:        String,
 }
 
 #[derive(Deserialize)]
@@ -79,7 +81,8 @@ impl Bitbucket {
 
             Ok(OAuth2User { id:       actual_uname.clone(),
                             username: actual_uname,
-                            email:    None, })
+                            email:    None,
+                            scopes:   Vec::new(), })
         } else {
             Err(Error::HttpResponse(status, body))
         }
@@ -114,16 +117,17 @@ impl OAuth2Provider for Bitbucket {
         let body = resp.text().await.map_err(Error::HttpClient)?;
         debug!("Bitbucket response body: {}", body);
 
-        let token = if status.is_success() {
+        let (token, scope) = if status.is_success() {
             match serde_json::from_str::<AuthOk>(&body) {
-                Ok(msg) => msg.access_token,
+                Ok(msg) => (msg.access_token, msg.scope),
                 Err(e) => return Err(Error::Serialization(e)),
             }
         } else {
             return Err(Error::HttpResponse(status, body));
         };
 
-        let user = self.user(config, client, &token).await?;
+        let mut user = self.user(config, client, &token).await?;
+        user.scopes = parse_scopes(&scope);
         Ok((token, user))
     }
 }
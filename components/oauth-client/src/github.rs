@@ -31,6 +31,8 @@ pub struct GitHub;
 #[derive(Deserialize)]
 struct AuthOk {
     pub access_token: String,
+    #[serde(default)]
+    pub scope:        String,
 }
 
 #[derive(Deserialize)]
@@ -68,7 +70,8 @@ impl GitHub {
 
             Ok(OAuth2User { id:       user.id.to_string(),
                             username: user.login,
-                            email:    user.email, })
+                            email:    user.email,
+                            scopes:   Vec::new(), })
         } else {
             Err(Error::HttpResponse(status, body))
         }
@@ -98,16 +101,17 @@ impl OAuth2Provider for GitHub {
         let body = resp.text().await.map_err(Error::HttpClient)?;
         debug!("GitHub response body: {}", body);
 
-        let token = if status.is_success() {
+        let (token, scope) = if status.is_success() {
             match serde_json::from_str::<AuthOk>(&body) {
-                Ok(msg) => msg.access_token,
+                Ok(msg) => (msg.access_token, msg.scope),
                 Err(e) => return Err(Error::Serialization(e)),
             }
         } else {
             return Err(Error::HttpResponse(status, body));
         };
 
-        let user = self.user(config, client, &token).await?;
+        let mut user = self.user(config, client, &token).await?;
+        user.scopes = parse_scopes(&scope);
         Ok((token, user))
     }
 }
@@ -30,6 +30,8 @@ pub struct GitLab;
 #[derive(Deserialize)]
 struct AuthOk {
     pub access_token: String,
+    #[serde(default)]
+    pub scope:        String,
 }
 
 #[derive(Deserialize)]
@@ -67,7 +69,8 @@ impl GitLab {
 
             Ok(OAuth2User { id:       user.sub,
                             username: user.nickname,
-                            email:    user.email, })
+                            email:    user.email,
+                            scopes:   Vec::new(), })
         } else {
             Err(Error::HttpResponse(status, body))
         }
@@ -102,16 +105,17 @@ impl OAuth2Provider for GitLab {
         let body = resp.text().await.map_err(Error::HttpClient)?;
         debug!("GitLab response body: {}", body);
 
-        let token = if status.is_success() {
+        let (token, scope) = if status.is_success() {
             match serde_json::from_str::<AuthOk>(&body) {
-                Ok(msg) => msg.access_token,
+                Ok(msg) => (msg.access_token, msg.scope),
                 Err(e) => return Err(Error::Serialization(e)),
             }
         } else {
             return Err(Error::HttpResponse(status, body));
         };
 
-        let user = self.user(config, client, &token).await?;
+        let mut user = self.user(config, client, &token).await?;
+        user.scopes = parse_scopes(&scope);
         Ok((token, user))
     }
 }
@@ -21,6 +21,17 @@ pub struct OAuth2User {
     pub id:       String,
     pub username: String,
     pub email:    Option<String>,
+    /// Scopes granted to the access token, if the provider reported them
+    pub scopes:   Vec<String>,
+}
+
+/// Split the `scope` of a token response into individual scopes. RFC 6749
+/// separates scopes with spaces, but GitHub uses commas, so both are accepted.
+pub fn parse_scopes(scope: &str) -> Vec<String> {
+    scope.split(|c: char| c == ',' || c.is_whitespace())
+         .filter(|s| !s.is_empty())
+         .map(str::to_string)
+         .collect()
 }
 
 #[async_trait]
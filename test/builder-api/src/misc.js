@@ -19,6 +19,20 @@ describe('Miscellanenous API', function () {
     });
   });
 
+  describe('Retrieving the API health', function () {
+    it('reports the datastore and memcache as healthy', function (done) {
+      request.get('/health')
+        .type('application/json')
+        .accept('application/json')
+        .expect(200)
+        .end(function (err, res) {
+          expect(res.body.db).to.equal(true);
+          expect(res.body.memcache).to.equal(true);
+          done(err);
+        });
+    });
+  });
+
   // We're going to simulate receiving a GH ping hook
   describe('Receiving a GitHub webhook', function () {
     it('succeeds', function (done) {
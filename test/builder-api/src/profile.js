@@ -133,4 +133,41 @@ describe('Profile API', function () {
         done(err);
       });
   });
+
+  it('no longer lists the revoked token', function (done) {
+    request.get('/profile/access-tokens')
+      .set('Authorization', global.boboBearer)
+      .type('application/json')
+      .accept('application/json')
+      .expect(200)
+      .end(function (err, res) {
+        expect(res.body.tokens).to.be.empty;
+        done(err);
+      });
+  });
+
+  describe('Revoking the current access token', function () {
+    it('requires authentication', function (done) {
+      request.delete('/profile/tokens')
+        .type('application/json')
+        .accept('application/json')
+        .expect(401)
+        .end(function (err, res) {
+          expect(res.text).to.be.empty;
+          done(err);
+        });
+    });
+
+    it('returns not found for a session without an access token', function (done) {
+      request.delete('/profile/tokens')
+        .set('Authorization', global.boboBearer)
+        .type('application/json')
+        .accept('application/json')
+        .expect(404)
+        .end(function (err, res) {
+          expect(res.text).to.be.empty;
+          done(err);
+        });
+    });
+  });
 });